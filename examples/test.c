@@ -1,4 +1,5 @@
 #include "std/io.h"
+#include <stdbool.h>
 
 //char *musica[30];
 
@@ -11,7 +12,7 @@ void main () {
 
   int p = 10;
   int c = 0;
-  while (1==1) {
+  while (true) {
     if (getc() == 'a') {
       int i = c;
       do {
@@ -1,8 +1,13 @@
 use crate::gen_ir::{Function, IROp, IR};
-use crate::{Scope, Var, REGS_N};
+use crate::matches;
+use crate::{Ctype, Data, Scope, Var, REGS_N};
 use std::io::Write;
 
-const REGS: [&str; REGS_N] = ["r1", "r2", "r3", "r4", "r5", "r6", "r7"];
+const REGS: [&str; REGS_N] = ["r1", "r2", "r3", "r4", "r5", "r6"];
+
+// Arguments on the stack are above the saved r0 and the return
+// address, so the first one is at r0 + 3.
+const STACK_ARGS: usize = 3;
 
 use std::sync::Mutex;
 
@@ -15,6 +20,8 @@ enum CMPS {
     NE,
     LT,
     LE,
+    GT,
+    GE,
 }
 
 macro_rules! emit{
@@ -22,26 +29,176 @@ macro_rules! emit{
     ($out:expr, $fmt:expr, $($arg:tt)*) => (write!($out, concat!("\t", $fmt, "\n"), $($arg)*).unwrap());
 }
 
-fn emit_cmp(output: &mut impl Write, ir: IR, cmp: CMPS) {
+// The condition of a comparison IR and whether it is signed.
+fn cmp_of(op: &IROp) -> (CMPS, bool) {
+    match op {
+        IROp::EQ => (CMPS::EQ, false),
+        IROp::NE => (CMPS::NE, false),
+        IROp::LT => (CMPS::LT, true),
+        IROp::LE => (CMPS::LE, true),
+        IROp::GT => (CMPS::GT, true),
+        IROp::GE => (CMPS::GE, true),
+        IROp::ULT => (CMPS::LT, false),
+        IROp::ULE => (CMPS::LE, false),
+        IROp::UGT => (CMPS::GT, false),
+        IROp::UGE => (CMPS::GE, false),
+        _ => unreachable!(),
+    }
+}
+
+// Compares lhs with rhs and returns the jump taken if the comparison
+// holds. `cmp` compares unsigned values. Signed values are compared
+// by flipping their sign bits first, which clobbers both operands,
+// but they are dead after a comparison other than for its result.
+fn emit_flags(
+    output: &mut impl Write,
+    lhs: usize,
+    rhs: usize,
+    cmp: CMPS,
+    signed: bool,
+) -> &'static str {
+    if signed {
+        emit!(output, "loadn r7, #32768");
+        emit!(output, "xor {}, {}, r7", REGS[lhs], REGS[lhs]);
+        emit!(output, "xor {}, {}, r7", REGS[rhs], REGS[rhs]);
+    }
+
+    let jmp = match cmp {
+        CMPS::EQ => "jeq",
+        CMPS::NE => "jne",
+        CMPS::LT => "jle",
+        CMPS::LE => "jel",
+        CMPS::GT => "jgr",
+        CMPS::GE => "jeg",
+    };
+    emit!(output, "cmp {}, {}", REGS[lhs], REGS[rhs]);
+    jmp
+}
+
+// There is no set-on-condition instruction, so lhs is set to 1 and
+// then cleared unless the conditional jump of the comparison is taken.
+// `loadn` leaves the flags alone.
+fn emit_cmp(output: &mut impl Write, ir: IR) {
     let lhs = ir.lhs.unwrap();
     let rhs = ir.rhs.unwrap();
+    let label = format!("Lcmp{}", *LABEL.lock().unwrap());
+    *LABEL.lock().unwrap() += 1;
 
-    emit!(output, "cmp {}, {}", REGS[lhs], REGS[rhs]);
-    emit!(output, "push fr");
-    emit!(output, "pop {}", REGS[lhs]);
-
-    let mask: u16 = match cmp {
-        CMPS::EQ => 0b100,
-        CMPS::NE => {
-            emit!(output, "not {}, {}", REGS[lhs], REGS[lhs]);
-            0b100
+    let (cmp, signed) = cmp_of(&ir.op);
+    let jmp = emit_flags(output, lhs, rhs, cmp, signed);
+    emit!(output, "loadn {}, #1", REGS[lhs]);
+    emit!(output, "{} {}", jmp, label);
+    emit!(output, "loadn {}, #0", REGS[lhs]);
+    writeln!(output, "{}:", label).unwrap();
+}
+
+// Negates a negative value in `reg`. r7 must hold 0x8000.
+fn emit_abs(output: &mut impl Write, reg: &str, label: &str) {
+    emit!(output, "cmp {}, r7", reg);
+    emit!(output, "jle {}", label);
+    emit!(output, "not {}, {}", reg, reg);
+    emit!(output, "inc {}", reg);
+    writeln!(output, "{}:", label).unwrap();
+}
+
+// `div` and `mod` work on unsigned values. A signed quotient is
+// computed from the absolute values of the operands and negated if
+// their signs differ. A remainder has the sign of the dividend.
+fn emit_signed_div(output: &mut impl Write, ir: IR, insn: &str) {
+    let lhs = REGS[ir.lhs.unwrap()];
+    let rhs = REGS[ir.rhs.unwrap()];
+    let n = *LABEL.lock().unwrap();
+    *LABEL.lock().unwrap() += 1;
+
+    if insn == "mod" {
+        emit!(output, "push {}", lhs);
+    } else {
+        emit!(output, "mov r7, {}", lhs);
+        emit!(output, "xor r7, r7, {}", rhs);
+        emit!(output, "push r7");
+    }
+
+    emit!(output, "loadn r7, #32768");
+    emit_abs(output, lhs, &format!("Ldiv{}a", n));
+    emit_abs(output, rhs, &format!("Ldiv{}b", n));
+    emit!(output, "{} {}, {}, {}", insn, lhs, lhs, rhs);
+
+    emit!(output, "pop {}", rhs);
+    emit!(output, "loadn r7, #32768");
+    emit!(output, "cmp {}, r7", rhs);
+    emit!(output, "jle Ldiv{}c", n);
+    emit!(output, "not {}, {}", lhs, lhs);
+    emit!(output, "inc {}", lhs);
+    writeln!(output, "Ldiv{}c:", n).unwrap();
+}
+
+// Shifts lhs by one bit with `insn` until the count in rhs runs out.
+// rhs must hold the count plus one.
+fn emit_shift_loop(output: &mut impl Write, lhs: &str, rhs: &str, insn: &str, label: &str) {
+    writeln!(output, "{}:", label).unwrap();
+    emit!(output, "dec {}", rhs);
+    emit!(output, "jz {}e", label);
+    emit!(output, "{} {}, #1", insn, lhs);
+    emit!(output, "jmp {}", label);
+}
+
+// Shift instructions only take an immediate count, so shifts by a
+// register are done one bit at a time. An arithmetic right shift
+// fills with ones if lhs is negative.
+fn emit_shift(output: &mut impl Write, ir: IR, insn: &str) {
+    let lhs = REGS[ir.lhs.unwrap()];
+    let rhs = REGS[ir.rhs.unwrap()];
+    let label = format!("Lsh{}", *LABEL.lock().unwrap());
+    *LABEL.lock().unwrap() += 1;
+
+    emit!(output, "inc {}", rhs);
+    if insn == "shiftr1" {
+        emit!(output, "loadn r7, #32768");
+        emit!(output, "cmp {}, r7", lhs);
+        emit!(output, "jle {}p", label);
+        emit_shift_loop(output, lhs, rhs, "shiftr1", &label);
+        emit_shift_loop(output, lhs, rhs, "shiftr0", &format!("{}p", label));
+        writeln!(output, "{}pe:", label).unwrap();
+    } else {
+        emit_shift_loop(output, lhs, rhs, insn, &label);
+    }
+    writeln!(output, "{}e:", label).unwrap();
+}
+
+// Calls a function by name, or through the target pushed by
+// PushTarget if `ret` has its return label. The first arguments are
+// passed in r1, r2, ... and the rest have already been pushed on the
+// stack. The return value is in r7.
+fn emit_call(
+    output: &mut impl Write,
+    lhs: usize,
+    ret: Option<usize>,
+    args: &[usize],
+    nstack: usize,
+    name: &str,
+) {
+    // Arguments may be in any register, so they are moved into place
+    // through the stack unless they already are.
+    if !args.iter().enumerate().all(|(i, r)| i == *r) {
+        for r in args {
+            emit!(output, "push {}", REGS[*r]);
         }
-        CMPS::LT => 0b10,
-        CMPS::LE => 0b110,
-    };
+        for i in (0..args.len()).rev() {
+            emit!(output, "pop {}", REGS[i]);
+        }
+    }
 
-    emit!(output, "loadn r7, #{}", mask);
-    emit!(output, "and {}, {}, r7", REGS[lhs], REGS[lhs]);
+    if let Some(ret) = ret {
+        emit!(output, "rts");
+        writeln!(output, "L{}:", ret).unwrap();
+    } else {
+        emit!(output, "call {}", name);
+    }
+
+    emit!(output, "mov {}, r7", REGS[lhs]);
+    for _ in 0..nstack {
+        emit!(output, "pop r7");
+    }
 }
 
 fn gen(output: &mut impl Write, f: Function) {
@@ -49,6 +206,8 @@ fn gen(output: &mut impl Write, f: Function) {
     let ret = format!("Lend{}", *LABEL.lock().unwrap());
     *LABEL.lock().unwrap() += 1;
 
+    let mut tables = vec![];
+
     writeln!(output, "{}:", f.name).unwrap();
 
     if f.stacksize > 0 {
@@ -60,7 +219,7 @@ fn gen(output: &mut impl Write, f: Function) {
     }
 
     for ir in f.ir {
-        let lhs = ir.lhs.unwrap();
+        let lhs = ir.lhs.unwrap_or(0);
         let rhs = ir.rhs.unwrap_or(0);
         match ir.op {
             Imm => emit!(output, "loadn {}, #{}", REGS[lhs], rhs as u16),
@@ -71,43 +230,122 @@ fn gen(output: &mut impl Write, f: Function) {
             }
             Outchar => emit!(output, "outchar {}, {}", REGS[lhs], REGS[rhs]),
             Inchar => emit!(output, "inchar {}", REGS[lhs]),
-            Call(name, nargs, _) => {
-                for i in nargs..lhs {
-                    emit!(output, "push {}", REGS[i]);
+            Call(name, args, nstack) => emit_call(output, lhs, None, &args, nstack, &name),
+            CallPtr(args, nstack) => emit_call(output, lhs, ir.rhs, &args, nstack, ""),
+            PushTarget(ret) => {
+                // There is no indirect call instruction. The return
+                // address and then the target are pushed, and `rts` pops
+                // the target into the program counter. `rts` skips the
+                // operand word of the `call` it returns from, hence both
+                // pushed addresses are one less.
+                emit!(output, "loadn r7, #L{}", ret);
+                emit!(output, "dec r7");
+                emit!(output, "push r7");
+                emit!(output, "mov r7, {}", REGS[lhs]);
+                emit!(output, "dec r7");
+                emit!(output, "push r7");
+            }
+            Save(regs) => {
+                for r in regs {
+                    emit!(output, "push {}", REGS[r]);
                 }
-                emit!(output, "call {}", name);
-                emit!(output, "mov {}, r7", REGS[lhs]);
-                for i in (nargs..lhs).rev() {
-                    emit!(output, "pop {}", REGS[i]);
+            }
+            Restore(regs) => {
+                for r in regs.into_iter().rev() {
+                    emit!(output, "pop {}", REGS[r]);
                 }
             }
+            Push => emit!(output, "push {}", REGS[lhs]),
+            Pop => emit!(output, "pop {}", REGS[lhs]),
             Label => writeln!(output, "L{}:", lhs).unwrap(),
             LabelAddr(name) => emit!(output, "loadn {}, #{}", REGS[lhs], name),
+            // An 8-bit value is sign extended by flipping its sign bit
+            // and subtracting it back.
+            Sext8 => {
+                emit!(output, "loadn r7, #255");
+                emit!(output, "and {}, {}, r7", REGS[lhs], REGS[lhs]);
+                emit!(output, "loadn r7, #128");
+                emit!(output, "xor {}, {}, r7", REGS[lhs], REGS[lhs]);
+                emit!(output, "sub {}, {}, r7", REGS[lhs], REGS[lhs]);
+            }
+            Zext8 => {
+                emit!(output, "loadn r7, #255");
+                emit!(output, "and {}, {}, r7", REGS[lhs], REGS[lhs]);
+            }
             Neg => {
                 emit!(output, "not {}, {}", REGS[lhs], REGS[lhs]);
                 emit!(output, "inc {}", REGS[lhs]);
             }
-            EQ => emit_cmp(output, ir, CMPS::EQ),
-            NE => emit_cmp(output, ir, CMPS::NE), // TODO
-            LT => emit_cmp(output, ir, CMPS::LT),
-            LE => emit_cmp(output, ir, CMPS::LE),
+            EQ | NE | LT | LE | GT | GE | ULT | ULE | UGT | UGE => emit_cmp(output, ir),
             AND => emit!(output, "and {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
             OR => emit!(output, "or {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
             XOR => emit!(output, "xor {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
-            SHL => emit!(output, "shiftl0 {}, {}", REGS[lhs], REGS[rhs]),
-            SHR => emit!(output, "shiftr0 {}, {}", REGS[lhs], REGS[rhs]),
-            Mod => emit!(output, "mod {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            SHL => emit_shift(output, ir, "shiftl0"),
+            SHR => emit_shift(output, ir, "shiftr0"),
+            SAR => emit_shift(output, ir, "shiftr1"),
+            Mod => emit_signed_div(output, ir, "mod"),
+            UMod => emit!(output, "mod {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
             Jmp => emit!(output, "jmp L{}", lhs),
-            If => emit!(output, "jnz L{}", rhs),
-            Unless => emit!(output, "jz L{}", rhs),
+            // The flags may not reflect lhs, so it is compared against 0.
+            If => {
+                emit!(output, "loadn r7, #0");
+                emit!(output, "cmp {}, r7", REGS[lhs]);
+                emit!(output, "jne L{}", rhs);
+            }
+            Unless => {
+                emit!(output, "loadn r7, #0");
+                emit!(output, "cmp {}, r7", REGS[lhs]);
+                emit!(output, "jeq L{}", rhs);
+            }
+            IfCmp(cmp, label) => {
+                let (cmp, signed) = cmp_of(&cmp);
+                let jmp = emit_flags(output, lhs, rhs, cmp, signed);
+                emit!(output, "{} L{}", jmp, label);
+            }
+            IfEqImm(val) => {
+                emit!(output, "loadn r7, #{}", val as u16);
+                emit!(output, "cmp {}, r7", REGS[lhs]);
+                emit!(output, "jeq L{}", rhs);
+            }
+            JmpTable(default, labels) => {
+                // There is no indirect jump instruction, so the target is
+                // pushed and popped into the program counter by `rts`.
+                // `rts` skips the operand word of the `call` it returns
+                // from, hence the pushed address is one less than the label.
+                let table = format!("Ljt{}", *LABEL.lock().unwrap());
+                *LABEL.lock().unwrap() += 1;
+
+                // `cmp` is unsigned, so indices below zero are out of range too.
+                emit!(output, "loadn r7, #{}", labels.len());
+                emit!(output, "cmp {}, r7", REGS[lhs]);
+                emit!(output, "jeg L{}", default);
+                emit!(output, "loadn r7, #{}", table);
+                emit!(output, "add r7, r7, {}", REGS[lhs]);
+                emit!(output, "loadi r7, r7");
+                emit!(output, "dec r7");
+                emit!(output, "push r7");
+                emit!(output, "rts");
+                tables.push((table, labels));
+            }
             Load(_) => emit!(output, "loadi {}, {}", REGS[lhs], REGS[rhs]),
             Store(_) => emit!(output, "storei {}, {}", REGS[lhs], REGS[rhs]),
+            StoreArg(_) if rhs >= REGS_N => {
+                // Arguments on the stack are stored after the ones in
+                // registers, so r1 is free by then.
+                emit!(output, "loadn r1, #{}", rhs - REGS_N + STACK_ARGS);
+                emit!(output, "add r1, r0, r1");
+                emit!(output, "loadi r1, r1");
+                emit!(output, "loadn r7, #{}", lhs);
+                emit!(output, "sub r7, r0, r7");
+                emit!(output, "storei r7, r1");
+            }
             StoreArg(_) => {
                 emit!(output, "loadn r7, #{}", lhs);
                 emit!(output, "sub r7, r0, r7");
                 emit!(output, "storei r7, {}", REGS[rhs]);
             }
             Add => emit!(output, "add {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            AddC => emit!(output, "addc {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
             AddImm => {
                 if rhs == 0 {
                     continue;
@@ -115,14 +353,8 @@ fn gen(output: &mut impl Write, f: Function) {
                 if rhs == 1 {
                     emit!(output, "inc {}", REGS[lhs]);
                 } else {
-                    emit!(output, "loadn {}, #{}", REGS[lhs + 1], rhs as u16);
-                    emit!(
-                        output,
-                        "add {}, {}, {}",
-                        REGS[lhs],
-                        REGS[lhs],
-                        REGS[lhs + 1]
-                    );
+                    emit!(output, "loadn r7, #{}", rhs as u16);
+                    emit!(output, "add {}, {}, r7", REGS[lhs], REGS[lhs]);
                 }
             }
             Sub => emit!(output, "sub {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
@@ -133,20 +365,18 @@ fn gen(output: &mut impl Write, f: Function) {
                 if rhs == 1 {
                     emit!(output, "dec {}", REGS[lhs]);
                 } else {
-                    emit!(output, "loadn {}, #{}", REGS[lhs + 1], rhs as u16);
-                    emit!(
-                        output,
-                        "sub {}, {}, {}",
-                        REGS[lhs],
-                        REGS[lhs],
-                        REGS[lhs + 1]
-                    );
+                    emit!(output, "loadn r7, #{}", rhs as u16);
+                    emit!(output, "sub {}, {}, r7", REGS[lhs], REGS[lhs]);
                 }
             }
             Bprel => {
                 emit!(output, "loadn {}, #{}", REGS[lhs], rhs);
                 emit!(output, "sub {}, r0, {}", REGS[lhs], REGS[lhs]);
             }
+            ArgAddr => {
+                emit!(output, "loadn {}, #{}", REGS[lhs], rhs + STACK_ARGS);
+                emit!(output, "add {}, r0, {}", REGS[lhs], REGS[lhs]);
+            }
             Mul => emit!(output, "mul {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
             MulImm => {
                 if rhs < 256 && rhs.count_ones() == 1 {
@@ -156,7 +386,8 @@ fn gen(output: &mut impl Write, f: Function) {
                     emit!(output, "mul {}, {}, r7", REGS[lhs], REGS[lhs]);
                 }
             }
-            Div => emit!(output, "div {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            Div => emit_signed_div(output, ir, "div"),
+            UDiv => emit!(output, "div {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
             Nop | Kill => (),
         }
     }
@@ -167,6 +398,53 @@ fn gen(output: &mut impl Write, f: Function) {
         emit!(output, "pop r0")
     }
     emit!(output, "rts");
+
+    for (table, labels) in tables {
+        writeln!(output, "{} : var #{}", table, labels.len()).unwrap();
+        for (i, l) in labels.iter().enumerate() {
+            writeln!(output, "static {} + #{}, #L{}", table, i, l).unwrap();
+        }
+    }
+}
+
+// Whether a global variable may be placed in read-only memory. An
+// array is const if its elements are.
+fn is_readonly(var: &Var) -> bool {
+    let mut ty = &*var.ty;
+    while let Ctype::Ary(ref ary_of, _) = ty.ty {
+        if ty.is_const {
+            break;
+        }
+        ty = ary_of;
+    }
+    ty.is_const && !var.init.is_empty()
+}
+
+fn emit_global(output: &mut impl Write, var: Var) {
+    if let Scope::Global(data, len, _) = var.scope {
+        if data.len() > 0 {
+            writeln!(output, "{} : string {:?}", var.name, data).unwrap();
+            return;
+        }
+
+        writeln!(output, "{} : var #{}", var.name, len).unwrap();
+        for (i, word) in var.init.iter().enumerate() {
+            match word {
+                Data::Num(0) => (),
+                Data::Num(val) => {
+                    writeln!(output, "static {} + #{}, #{}", var.name, i, *val as u16).unwrap()
+                }
+                Data::Addr(name, 0) => {
+                    writeln!(output, "static {} + #{}, #{}", var.name, i, name).unwrap()
+                }
+                Data::Addr(name, off) => {
+                    writeln!(output, "static {} + #{}, #{} + {}", var.name, i, name, off).unwrap()
+                }
+            }
+        }
+        return;
+    }
+    unreachable!();
 }
 
 pub fn gen_asm(output: &mut impl Write, globals: Vec<Var>, fns: Vec<Function>) {
@@ -177,20 +455,21 @@ pub fn gen_asm(output: &mut impl Write, globals: Vec<Var>, fns: Vec<Function>) {
         gen(output, f);
     }
 
-    for var in globals {
-        if let Scope::Global(data, len, is_extern) = var.scope {
-            if is_extern {
-                continue;
-            }
+    // Initialized const globals are emitted after the other ones, in a
+    // block of their own, so that they can be told apart as ROM.
+    let (rom, ram): (Vec<Var>, Vec<Var>) = globals
+        .into_iter()
+        .filter(|var| !matches!(var.scope, Scope::Global(_, _, true)))
+        .partition(is_readonly);
 
-            if data.len() > 0 {
-                writeln!(output, "{} : string {:?}", var.name, data).unwrap();
-            } else {
-                writeln!(output, "{} : var #{}", var.name, len).unwrap();
-            }
+    for var in ram {
+        emit_global(output, var);
+    }
 
-            continue;
+    if !rom.is_empty() {
+        writeln!(output, "; .rodata").unwrap();
+        for var in rom {
+            emit_global(output, var);
         }
-        unreachable!();
     }
 }
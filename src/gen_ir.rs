@@ -10,17 +10,37 @@
 // > Such infinite number of registers are mapped to a finite registers
 // > in a later pass.
 
+use crate::matches;
 use crate::parse::{Node, NodeType};
-use crate::{Ctype, Scope, TokenType, Type};
+use crate::{Ctype, Scope, TokenType, Type, REGS_N};
 
+use std::collections::HashMap;
+use std::mem;
 use std::sync::Mutex;
 
+// A switch is lowered to a jump table when it has at least this many
+// cases and the case values cover at least a third of their range.
+// Otherwise it is lowered to a chain of compares.
+const JMP_TABLE_MIN_CASES: usize = 4;
+const JMP_TABLE_MAX_SPREAD: usize = 3;
+
+// A long returned by a function or a runtime helper has its low word
+// in r7 and its high word in this global of std/long.asm.
+const LONG_HI: &str = "__lhi";
+
+// The registers of the low and high words of a long.
+type LongRegs = (Option<usize>, Option<usize>);
+
 lazy_static! {
     static ref NUM_REGS: Mutex<usize> = Mutex::new(0);
     static ref NLABEL: Mutex<usize> = Mutex::new(1);
     static ref RETURN_LABEL: Mutex<usize> = Mutex::new(0);
     static ref RETURN_REG: Mutex<usize> = Mutex::new(0);
     static ref BREAK_LABEL: Mutex<usize> = Mutex::new(0);
+    static ref CONTINUE_LABEL: Mutex<usize> = Mutex::new(0);
+    static ref CASE_LABELS: Mutex<Vec<usize>> = Mutex::new(vec![]);
+    static ref DEFAULT_LABEL: Mutex<usize> = Mutex::new(0);
+    static ref USER_LABELS: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
     static ref CODE: Mutex<Vec<IR>> = Mutex::new(vec![]);
 }
 
@@ -29,6 +49,12 @@ fn add(op: IROp, lhs: Option<usize>, rhs: Option<usize>) {
     CODE.lock().unwrap().push(ir.clone());
 }
 
+fn new_reg() -> Option<usize> {
+    let r = Some(*NUM_REGS.lock().unwrap());
+    *NUM_REGS.lock().unwrap() += 1;
+    r
+}
+
 #[derive(Clone, Debug)]
 pub enum IRType {
     Noarg,
@@ -42,7 +68,9 @@ pub enum IRType {
     RegImm,
     StoreArg,
     RegLabel,
+    JmpTable,
     Call,
+    RegList,
 }
 
 #[derive(Clone, Debug)]
@@ -65,35 +93,59 @@ impl Function {
 #[derive(Debug, Clone, PartialEq)]
 pub enum IROp {
     Add,
+    AddC,
     AddImm,
     Sub,
     SubImm,
     Mul,
     MulImm,
     Div,
+    UDiv,
     Imm,
     Bprel,
+    ArgAddr,
     Mov,
     Return,
     Outchar,
     Inchar,
-    Call(String, usize, [usize; 6]),
+    // A call kills its arguments, so that the result may be placed in
+    // one of them.
+    Call(String, Vec<usize>, usize), // name, register args, number of stack args
+    CallPtr(Vec<usize>, usize),      // register args, number of stack args
+    PushTarget(usize),               // return label of the CallPtr
+    Save(Vec<usize>),                // registers live across a call
+    Restore(Vec<usize>),             // registers saved by the matching Save
+    Push,
+    Pop,
     Label,
     LabelAddr(String),
     EQ,
     NE,
     LE,
     LT,
+    GE,
+    GT,
+    ULE,
+    ULT,
+    UGE,
+    UGT,
     AND,
     OR,
     XOR,
     SHL,
     SHR,
+    SAR,
     Mod,
+    UMod,
     Neg,
+    Sext8,
+    Zext8,
     Jmp,
     If,
     Unless,
+    IfEqImm(i32),
+    IfCmp(Box<IROp>, usize), // comparison of lhs and rhs, target label
+    JmpTable(usize, Vec<usize>),
     Load(u8),
     Store(u8),
     StoreArg(u8),
@@ -117,7 +169,12 @@ impl From<TokenType> for IROp {
             TokenType::Minus => IROp::Sub,
             TokenType::Mul => IROp::Mul,
             TokenType::Div => IROp::Div,
-            TokenType::LeftAngleBracket | TokenType::RightAngleBracket => IROp::LT,
+            TokenType::LeftAngleBracket => IROp::LT,
+            TokenType::LE => IROp::LE,
+            TokenType::RightAngleBracket => IROp::GT,
+            TokenType::GE => IROp::GE,
+            TokenType::EQ => IROp::EQ,
+            TokenType::NE => IROp::NE,
             e => panic!("cannot convert: {:?}", e),
         }
     }
@@ -150,6 +207,38 @@ fn jmp(x: Option<usize>) {
 
 fn load(ty: &Type, dst: Option<usize>, src: Option<usize>) {
     add(IROp::Load(ty.size as u8), dst, src);
+    // A char in memory may have its upper bits set.
+    if ty.is_char() {
+        truncate(ty, dst);
+    }
+}
+
+fn is_bool(ty: &Type) -> bool {
+    matches!(ty.ty, Ctype::Bool)
+}
+
+// Compares the value in `r` against 0, as `op` does.
+fn cmp_zero(op: IROp, r: Option<usize>) {
+    let zero = new_reg();
+    add(IROp::Imm, zero, Some(0));
+    add(op, r, zero);
+    kill(zero);
+}
+
+// Truncates the value in `r` to the type `ty`. A char is cut to 8 bits
+// and its sign extended if it is signed, and a _Bool is 1 unless the
+// value is 0.
+fn truncate(ty: &Type, r: Option<usize>) {
+    if ty.is_char() {
+        let op = if ty.is_unsigned {
+            IROp::Zext8
+        } else {
+            IROp::Sext8
+        };
+        add(op, r, None);
+    } else if is_bool(ty) {
+        cmp_zero(IROp::NE, r);
+    }
 }
 
 fn store(ty: &Type, dst: Option<usize>, src: Option<usize>) {
@@ -199,10 +288,96 @@ fn gen_lval(node: Box<Node>) -> Option<usize> {
             add(IROp::LabelAddr(name), r, None);
             r
         }
+        // A struct returned by a call or selected by `?:` has no name,
+        // but it is still referred to by its address.
+        _ if is_struct(&node.ty) => gen_expr(node),
         _ => unreachable!(),
     }
 }
 
+// A struct does not fit in a register, so an expression of struct
+// type evaluates to the address of the struct instead of its value.
+// So does an array that has not decayed, such as the one initializing
+// a local array.
+fn is_struct(ty: &Type) -> bool {
+    matches!(ty.ty, Ctype::Struct(_, _, _))
+}
+
+fn is_aggregate(ty: &Type) -> bool {
+    is_struct(ty) || matches!(ty.ty, Ctype::Ary(_, _))
+}
+
+fn load_value(ty: &Type, dst: Option<usize>, src: Option<usize>) {
+    if !is_aggregate(ty) {
+        load(ty, dst, src);
+    }
+}
+
+// Copies `size` words from the address in `src` to the one in `dst`.
+// Both registers hold their original addresses again afterwards.
+fn gen_copy(size: usize, dst: Option<usize>, src: Option<usize>) {
+    let n = Some(*NUM_REGS.lock().unwrap());
+    *NUM_REGS.lock().unwrap() += 1;
+    let val = Some(*NUM_REGS.lock().unwrap());
+    *NUM_REGS.lock().unwrap() += 1;
+    let x = Some(*NLABEL.lock().unwrap());
+    *NLABEL.lock().unwrap() += 1;
+
+    add(IROp::Imm, n, Some(size));
+    label(x);
+    add(IROp::Load(1), val, src);
+    add(IROp::Store(1), dst, val);
+    add(IROp::AddImm, src, Some(1));
+    add(IROp::AddImm, dst, Some(1));
+    add(IROp::SubImm, n, Some(1));
+    add(IROp::If, n, x);
+    kill(val);
+    kill(n);
+
+    add(IROp::SubImm, src, Some(size));
+    add(IROp::SubImm, dst, Some(size));
+}
+
+// Pointers and unsigned integers are compared, divided and shifted
+// as unsigned values.
+fn is_unsigned(ty: &Type) -> bool {
+    ty.is_unsigned || matches!(ty.ty, Ctype::Ptr(_)) || matches!(ty.ty, Ctype::Ary(_, _))
+}
+
+// Returns the unsigned variant of a signed operation.
+fn to_unsigned_op(op: IROp, unsigned: bool) -> IROp {
+    if !unsigned {
+        return op;
+    }
+    match op {
+        IROp::LT => IROp::ULT,
+        IROp::LE => IROp::ULE,
+        IROp::GT => IROp::UGT,
+        IROp::GE => IROp::UGE,
+        IROp::Div => IROp::UDiv,
+        IROp::Mod => IROp::UMod,
+        IROp::SAR => IROp::SHR,
+        op => op,
+    }
+}
+
+// The comparison that is true when `op` is false.
+fn negate_cmp(op: IROp) -> IROp {
+    match op {
+        IROp::EQ => IROp::NE,
+        IROp::NE => IROp::EQ,
+        IROp::LT => IROp::GE,
+        IROp::LE => IROp::GT,
+        IROp::GT => IROp::LE,
+        IROp::GE => IROp::LT,
+        IROp::ULT => IROp::UGE,
+        IROp::ULE => IROp::UGT,
+        IROp::UGT => IROp::ULE,
+        IROp::UGE => IROp::ULT,
+        op => panic!("not a comparison: {:?}", op),
+    }
+}
+
 fn gen_binop(ty: IROp, lhs: Box<Node>, rhs: Box<Node>) -> Option<usize> {
     let r1 = gen_expr(lhs);
     let r2 = gen_expr(rhs);
@@ -228,18 +403,36 @@ fn gen_pre_inc(ty: &Type, expr: Box<Node>, num: i32) -> i32 {
         Some(val),
         Some(num as usize * get_inc_scale(ty)),
     );
+    truncate(ty, Some(val));
     store(ty, addr, Some(val));
     kill(addr);
     val as i32
 }
 
 fn gen_post_inc(ty: &Type, expr: Box<Node>, num: i32) -> i32 {
+    // The old value of a _Bool cannot be told from the new one, so it
+    // is kept apart.
+    if is_bool(ty) {
+        let addr = gen_lval(expr);
+        let val = new_reg();
+        let new = new_reg();
+        load(ty, val, addr);
+        add(IROp::Mov, new, val);
+        add(IROp::AddImm, new, Some(num as usize));
+        truncate(ty, new);
+        store(ty, addr, new);
+        kill(new);
+        kill(addr);
+        return val.unwrap() as i32;
+    }
+
     let val = gen_pre_inc(ty, expr, num);
     add(
         IROp::SubImm,
         Some(val as usize),
         Some(num as usize * get_inc_scale(ty)),
     );
+    truncate(ty, Some(val as usize));
     val as i32
 }
 
@@ -252,7 +445,7 @@ fn to_assign_op(op: &TokenType) -> IROp {
         AddEQ => IROp::Add,
         SubEQ => IROp::Sub,
         ShlEQ => IROp::SHL,
-        ShrEQ => IROp::SHR,
+        ShrEQ => IROp::SAR,
         BitandEQ => IROp::AND,
         XorEQ => IROp::XOR,
         BitorEQ => IROp::OR,
@@ -261,20 +454,503 @@ fn to_assign_op(op: &TokenType) -> IROp {
 }
 
 fn gen_assign_op(op: &TokenType, ty: &Type, lhs: Box<Node>, rhs: Box<Node>) -> Option<usize> {
+    let unsigned = if *op == TokenType::ShrEQ {
+        is_unsigned(&lhs.ty)
+    } else {
+        is_unsigned(&lhs.ty) || is_unsigned(&rhs.ty)
+    };
     let src = gen_expr(rhs);
     let dst = gen_lval(lhs);
     let val = Some(*NUM_REGS.lock().unwrap());
     *NUM_REGS.lock().unwrap() += 1;
 
     load(ty, val, dst);
-    add(to_assign_op(op), val, src);
+    add(to_unsigned_op(to_assign_op(op), unsigned), val, src);
     kill(src);
+    truncate(ty, val);
     store(ty, dst, val);
     kill(dst);
     val
 }
 
+// Returns the word of the arguments each argument of type `tys`
+// starts at, counting from `first`. The first REGS_N words are passed
+// in registers and the rest on the stack. A long takes two words, and
+// it is passed on the stack if only one register is left.
+fn arg_words<'a>(tys: impl Iterator<Item = &'a Type>, first: usize) -> Vec<usize> {
+    let mut word = first;
+    let mut words = vec![];
+    for ty in tys {
+        let size = if is_long(ty) { 2 } else { 1 };
+        if word < REGS_N && word + size > REGS_N {
+            word = REGS_N;
+        }
+        words.push(word);
+        word += size;
+    }
+    words
+}
+
+// Arguments that do not fit in registers are pushed on the stack as
+// soon as they are evaluated, the last one first. Returns how many
+// words were pushed. All the arguments of a variadic function are
+// passed on the stack, so that they can be walked through by va_arg.
+fn push_stack_args(args: &mut Vec<Node>, is_variadic: bool) -> usize {
+    let first = if is_variadic { REGS_N } else { 0 };
+    let words = arg_words(args.iter().map(|arg| &*arg.ty), first);
+    let nregs = words.iter().filter(|word| **word < REGS_N).count();
+    let stack_args = args.split_off(nregs);
+    let mut nstack = 0;
+    for arg in stack_args.iter().rev() {
+        if is_long(&arg.ty) {
+            let (lo, hi) = gen_long(Box::new(arg.clone()));
+            add(IROp::Push, hi, None);
+            add(IROp::Push, lo, None);
+            kill(hi);
+            kill(lo);
+            nstack += 2;
+        } else {
+            let r = gen_expr(Box::new(arg.clone()));
+            add(IROp::Push, r, None);
+            kill(r);
+            nstack += 1;
+        }
+    }
+    nstack
+}
+
+fn gen_args(args: Vec<Node>) -> Vec<usize> {
+    let mut regs = vec![];
+    for arg in args {
+        if is_long(&arg.ty) {
+            let (lo, hi) = gen_long(Box::new(arg));
+            regs.push(lo.unwrap());
+            regs.push(hi.unwrap());
+        } else {
+            regs.push(gen_expr(Box::new(arg)).unwrap());
+        }
+    }
+    regs
+}
+
+fn gen_call(op: NodeType) -> Option<usize> {
+    match op {
+        NodeType::Call(name, mut args, is_variadic) => {
+            // The callee may use any register, so the ones in use are
+            // saved before the stack arguments are pushed.
+            add(IROp::Save(vec![]), None, None);
+            let nstack = push_stack_args(&mut args, is_variadic);
+            let args_ir = gen_args(args);
+
+            let r = Some(*NUM_REGS.lock().unwrap());
+            *NUM_REGS.lock().unwrap() += 1;
+
+            add(IROp::Call(name, args_ir, nstack), r, None);
+            add(IROp::Restore(vec![]), None, None);
+            r
+        }
+        NodeType::CallPtr(expr, mut args) => {
+            let is_variadic = match expr.ty.ty {
+                Ctype::Ptr(ref ptr_to) => matches!(ptr_to.ty, Ctype::Func(_, _, true)),
+                _ => unreachable!(),
+            };
+            add(IROp::Save(vec![]), None, None);
+            let nstack = push_stack_args(&mut args, is_variadic);
+
+            // The callee is pushed before the register arguments are
+            // evaluated, so that it does not take up a register.
+            let ret = *NLABEL.lock().unwrap();
+            *NLABEL.lock().unwrap() += 1;
+            let callee = gen_expr(expr);
+            add(IROp::PushTarget(ret), callee, None);
+            kill(callee);
+
+            let args_ir = gen_args(args);
+
+            let r = Some(*NUM_REGS.lock().unwrap());
+            *NUM_REGS.lock().unwrap() += 1;
+
+            add(IROp::CallPtr(args_ir, nstack), r, Some(ret));
+            add(IROp::Restore(vec![]), None, None);
+            r
+        }
+        _ => unreachable!(),
+    }
+}
+
+// A long does not fit in a register, so it is evaluated into a pair of
+// registers, the low word and the high word. Multiplication, division,
+// shifts and ordering comparisons of longs call runtime helpers of
+// std/long.asm, which take the words of their operands in order.
+fn is_long(ty: &Type) -> bool {
+    matches!(ty.ty, Ctype::Long)
+}
+
+// Loads the long at the address in `addr`. The register of the address
+// holds the high word afterwards.
+fn load_long(addr: Option<usize>) -> (Option<usize>, Option<usize>) {
+    let lo = new_reg();
+    load(&Type::int_ty(), lo, addr);
+    add(IROp::AddImm, addr, Some(1));
+    load(&Type::int_ty(), addr, addr);
+    (lo, addr)
+}
+
+// Stores a long at the address in `addr`, which is changed.
+fn store_long(addr: Option<usize>, lo: Option<usize>, hi: Option<usize>) {
+    store(&Type::int_ty(), addr, lo);
+    add(IROp::AddImm, addr, Some(1));
+    store(&Type::int_ty(), addr, hi);
+}
+
+fn load_long_hi() -> Option<usize> {
+    let r = new_reg();
+    add(IROp::LabelAddr(LONG_HI.into()), r, None);
+    load(&Type::int_ty(), r, r);
+    r
+}
+
+// Calls a runtime helper with the argument registers returned by
+// `gen_args`, which are evaluated after the registers in use are saved.
+fn gen_helper_call(name: &str, gen_args: impl FnOnce() -> Vec<usize>) -> Option<usize> {
+    add(IROp::Save(vec![]), None, None);
+    let args = gen_args();
+    let r = new_reg();
+    add(IROp::Call(name.into(), args, 0), r, None);
+    add(IROp::Restore(vec![]), None, None);
+    r
+}
+
+fn gen_long_operands(lhs: Box<Node>, rhs: Box<Node>) -> Vec<usize> {
+    let ((al, ah), (bl, bh)) = gen_long_pair(lhs, rhs);
+    vec![al.unwrap(), ah.unwrap(), bl.unwrap(), bh.unwrap()]
+}
+
+// Estimates the number of registers needed to evaluate a long, which
+// keeps two of them live. A call saves the registers in use, so it only
+// needs the ones of its result.
+fn long_regs(node: &Node) -> usize {
+    use self::TokenType::*;
+    match node.op {
+        NodeType::BinOp(ref op @ Plus, ref lhs, ref rhs)
+        | NodeType::BinOp(ref op @ Minus, ref lhs, ref rhs)
+        | NodeType::BinOp(ref op @ And, ref lhs, ref rhs)
+        | NodeType::BinOp(ref op @ VerticalBar, ref lhs, ref rhs)
+        | NodeType::BinOp(ref op @ Hat, ref lhs, ref rhs) => {
+            let (a, b) = (long_regs(lhs), long_regs(rhs));
+            let (first, second) = (a.max(b), a.min(b));
+            let need = if 2 + second > REGS_N {
+                first
+            } else {
+                first.max(2 + second)
+            };
+            // A subtraction needs one more for the borrow.
+            if *op == Minus {
+                need.max(5)
+            } else {
+                need.max(4)
+            }
+        }
+        NodeType::BinOp(Equal, _, ref rhs) => long_regs(rhs) + 1,
+        NodeType::BinOp(Comma, _, ref rhs) => long_regs(rhs),
+        NodeType::Cast(ref expr) if !is_long(&expr.ty) => 3,
+        NodeType::Ternary(_, ref then, ref els) => long_regs(then).max(long_regs(els)) + 2,
+        _ => 2,
+    }
+}
+
+// Evaluates two long operands, the one needing more registers first.
+// There is no other spilling, so if the other one does not fit in the
+// registers left, the first one is pushed on the stack meanwhile.
+fn gen_long_pair(lhs: Box<Node>, rhs: Box<Node>) -> (LongRegs, LongRegs) {
+    let swap = long_regs(&rhs) > long_regs(&lhs);
+    let (first, second) = if swap { (rhs, lhs) } else { (lhs, rhs) };
+    let spill = 2 + long_regs(&second) > REGS_N;
+
+    let (mut lo, mut hi) = gen_long(first);
+    if spill {
+        add(IROp::Push, hi, None);
+        add(IROp::Push, lo, None);
+        kill(lo);
+        kill(hi);
+    }
+    let b = gen_long(second);
+    if spill {
+        lo = new_reg();
+        hi = new_reg();
+        add(IROp::Pop, lo, None);
+        add(IROp::Pop, hi, None);
+    }
+
+    if swap {
+        (b, (lo, hi))
+    } else {
+        ((lo, hi), b)
+    }
+}
+
+// The carry of the low words is added to the high ones by `addc`,
+// which must follow the `add` of the low words right away.
+fn gen_long_add(a: (Option<usize>, Option<usize>), b: (Option<usize>, Option<usize>)) {
+    add(IROp::Add, a.0, b.0);
+    add(IROp::AddC, a.1, b.1);
+    kill(b.0);
+    kill(b.1);
+}
+
+fn gen_long_sub(a: (Option<usize>, Option<usize>), b: (Option<usize>, Option<usize>)) {
+    let borrow = new_reg();
+    add(IROp::Mov, borrow, a.0);
+    add(IROp::ULT, borrow, b.0);
+    add(IROp::Sub, a.0, b.0);
+    add(IROp::Sub, a.1, b.1);
+    add(IROp::Sub, a.1, borrow);
+    kill(borrow);
+    kill(b.0);
+    kill(b.1);
+}
+
+fn gen_long_binop(
+    op: TokenType,
+    unsigned: bool,
+    lhs: Box<Node>,
+    rhs: Box<Node>,
+) -> (Option<usize>, Option<usize>) {
+    use self::TokenType::*;
+    match op {
+        Equal => {
+            let (lo, hi) = gen_long(rhs);
+            let addr = gen_lval(lhs);
+            store_long(addr, lo, hi);
+            kill(addr);
+            (lo, hi)
+        }
+        Comma => {
+            kill(gen_expr(lhs));
+            gen_long(rhs)
+        }
+        Mul | Div | Mod => {
+            let name = match op {
+                Mul => "__lmul",
+                Div if unsigned => "__ludiv",
+                Div => "__ldiv",
+                Mod if unsigned => "__lumod",
+                _ => "__lmod",
+            };
+            let lo = gen_helper_call(name, || gen_long_operands(lhs, rhs));
+            (lo, load_long_hi())
+        }
+        SHL | SHR => {
+            let name = match op {
+                SHL => "__lshl",
+                SHR if unsigned => "__lshr",
+                _ => "__lsar",
+            };
+            let lo = gen_helper_call(name, || {
+                let (lo, hi) = gen_long(lhs);
+                vec![lo.unwrap(), hi.unwrap(), gen_expr(rhs).unwrap()]
+            });
+            (lo, load_long_hi())
+        }
+        _ => {
+            let (a, b) = gen_long_pair(lhs, rhs);
+            match op {
+                Plus => gen_long_add(a, b),
+                Minus => gen_long_sub(a, b),
+                And | VerticalBar | Hat => {
+                    let op = match op {
+                        And => IROp::AND,
+                        VerticalBar => IROp::OR,
+                        _ => IROp::XOR,
+                    };
+                    add(op.clone(), a.0, b.0);
+                    add(op, a.1, b.1);
+                    kill(b.0);
+                    kill(b.1);
+                }
+                e => unreachable!("{:?}", e),
+            }
+            a
+        }
+    }
+}
+
+// Compares two longs. Their words are compared as a whole for
+// equality, and __lcmp returns -1, 0 or 1 as lhs is less than, equal
+// to or greater than rhs, which is then compared against 0.
+fn gen_long_cmp(op: TokenType, lhs: Box<Node>, rhs: Box<Node>) -> Option<usize> {
+    let unsigned = lhs.ty.is_unsigned;
+    let (r, cmp, val) = match op {
+        TokenType::EQ | TokenType::NE => {
+            let ((al, ah), (bl, bh)) = gen_long_pair(lhs, rhs);
+            add(IROp::XOR, al, bl);
+            add(IROp::XOR, ah, bh);
+            add(IROp::OR, al, ah);
+            kill(bl);
+            kill(bh);
+            kill(ah);
+            let cmp = if op == TokenType::EQ {
+                IROp::EQ
+            } else {
+                IROp::NE
+            };
+            (al, cmp, 0)
+        }
+        _ => {
+            let name = if unsigned { "__lucmp" } else { "__lcmp" };
+            let r = gen_helper_call(name, || gen_long_operands(lhs, rhs));
+            (r, IROp::from(op), 0)
+        }
+    };
+    let r2 = new_reg();
+    add(IROp::Imm, r2, Some(val));
+    add(cmp, r, r2);
+    kill(r2);
+    r
+}
+
+fn gen_long(node: Box<Node>) -> (Option<usize>, Option<usize>) {
+    match node.op {
+        NodeType::Num(val) => {
+            let lo = new_reg();
+            let hi = new_reg();
+            add(IROp::Imm, lo, Some(val as usize));
+            add(IROp::Imm, hi, Some((val >> 16) as usize));
+            (lo, hi)
+        }
+        NodeType::Lvar(_) | NodeType::Dot(_, _, _) | NodeType::Gvar(_, _, _) => {
+            load_long(gen_lval(node))
+        }
+        NodeType::Deref(expr) => load_long(gen_expr(expr)),
+        NodeType::Call(_, _, _) | NodeType::CallPtr(_, _) => {
+            let lo = gen_call(node.op);
+            (lo, load_long_hi())
+        }
+        // An unsigned value is extended with zeros and a signed one
+        // with copies of its sign bit, which is 0 - (lo < 0).
+        NodeType::Cast(expr) => {
+            if is_long(&expr.ty) {
+                return gen_long(expr);
+            }
+            let unsigned = is_unsigned(&expr.ty);
+            let lo = gen_expr(expr);
+            let hi = new_reg();
+            if unsigned {
+                add(IROp::Imm, hi, Some(0));
+            } else {
+                let zero = new_reg();
+                add(IROp::Mov, hi, lo);
+                add(IROp::Imm, zero, Some(0));
+                add(IROp::LT, hi, zero);
+                kill(zero);
+                add(IROp::Neg, hi, None);
+            }
+            (lo, hi)
+        }
+        // -x is (-lo, -hi - borrow), with a borrow if lo is not 0.
+        NodeType::Neg(expr) => {
+            let (lo, hi) = gen_long(expr);
+            let borrow = new_reg();
+            add(IROp::Imm, borrow, Some(0));
+            add(IROp::ULT, borrow, lo);
+            add(IROp::Neg, lo, None);
+            add(IROp::Neg, hi, None);
+            add(IROp::Sub, hi, borrow);
+            kill(borrow);
+            (lo, hi)
+        }
+        NodeType::VaArg(ap) => {
+            let addr = gen_lval(ap);
+            let p = new_reg();
+            let lo = new_reg();
+            let hi = new_reg();
+            load(&Type::int_ty(), p, addr);
+            load(&Type::int_ty(), lo, p);
+            add(IROp::AddImm, p, Some(1));
+            load(&Type::int_ty(), hi, p);
+            add(IROp::AddImm, p, Some(1));
+            store(&Type::int_ty(), addr, p);
+            kill(p);
+            kill(addr);
+            (lo, hi)
+        }
+        NodeType::Ternary(cond, then, els) => {
+            let x = Some(*NLABEL.lock().unwrap());
+            *NLABEL.lock().unwrap() += 1;
+            let y = Some(*NLABEL.lock().unwrap());
+            *NLABEL.lock().unwrap() += 1;
+            let lo = gen_cond(cond);
+            let hi = new_reg();
+
+            add(IROp::Unless, lo, x);
+            let (lo2, hi2) = gen_long(then);
+            add(IROp::Mov, lo, lo2);
+            add(IROp::Mov, hi, hi2);
+            kill(lo2);
+            kill(hi2);
+            jmp(y);
+
+            label(x);
+            let (lo3, hi3) = gen_long(els);
+            add(IROp::Mov, lo, lo3);
+            add(IROp::Mov, hi, hi3);
+            kill(lo3);
+            kill(hi3);
+            label(y);
+            (lo, hi)
+        }
+        NodeType::BinOp(op, lhs, rhs) => gen_long_binop(op, node.ty.is_unsigned, lhs, rhs),
+        e => unreachable!("{:?}", e),
+    }
+}
+
+// Evaluates a value tested as a condition. A long used as a truth value
+// is nonzero if either word is.
+fn gen_cond(node: Box<Node>) -> Option<usize> {
+    if is_long(&node.ty) {
+        let (lo, hi) = gen_long(node);
+        add(IROp::OR, lo, hi);
+        kill(hi);
+        return lo;
+    }
+    gen_expr(node)
+}
+
+// Jumps to `x` if `cond` is `when`. A comparison of ints branches
+// on its result directly rather than on a value of 0 or 1.
+fn gen_branch(cond: Box<Node>, x: Option<usize>, when: bool) {
+    if let NodeType::BinOp(ref op, ref lhs, ref rhs) = cond.op {
+        use self::TokenType::*;
+        if matches!(op, EQ | NE | LeftAngleBracket | LE | RightAngleBracket | GE)
+            && !is_long(&lhs.ty)
+        {
+            let unsigned = is_unsigned(&lhs.ty) || is_unsigned(&rhs.ty);
+            let mut cmp = to_unsigned_op(IROp::from(op.clone()), unsigned);
+            if !when {
+                cmp = negate_cmp(cmp);
+            }
+            let r1 = gen_expr(lhs.clone());
+            let r2 = gen_expr(rhs.clone());
+            add(IROp::IfCmp(Box::new(cmp), x.unwrap()), r1, r2);
+            kill(r1);
+            kill(r2);
+            return;
+        }
+    }
+    let r = gen_cond(cond);
+    add(if when { IROp::If } else { IROp::Unless }, r, x);
+    kill(r);
+}
+
 fn gen_expr(node: Box<Node>) -> Option<usize> {
+    // A long whose value is discarded, such as an assignment used as a
+    // statement, is evaluated for its side effects.
+    if is_long(&node.ty) {
+        let (lo, hi) = gen_long(node);
+        kill(hi);
+        return lo;
+    }
+
     let node = *node;
     match node.op {
         NodeType::Num(val) => {
@@ -285,25 +961,38 @@ fn gen_expr(node: Box<Node>) -> Option<usize> {
         }
         NodeType::Lvar(_) | NodeType::Dot(_, _, _) | NodeType::Gvar(_, _, _) => {
             let r = gen_lval(Box::new(node.clone()));
-            load(&node.ty, r, r);
+            load_value(&node.ty, r, r);
             r
         }
-        NodeType::Call(name, args) => {
-            let mut args_ir: [usize; 6] = [0; 6];
-            for i in 0..args.len() {
-                args_ir[i] = gen_expr(Box::new(args[i].clone())).unwrap();
-            }
-
+        NodeType::Call(_, _, _) | NodeType::CallPtr(_, _) => gen_call(node.op),
+        // A va_list points to the next variable argument on the stack,
+        // which follows the named ones.
+        NodeType::VaStart(ap, nparams) => {
             let r = Some(*NUM_REGS.lock().unwrap());
             *NUM_REGS.lock().unwrap() += 1;
-
-            add(IROp::Call(name, args.len(), args_ir), r, None);
-
-            for arg in args_ir.iter().take(args.len()) {
-                kill(Some(*arg));
-            }
+            add(IROp::ArgAddr, r, Some(nparams));
+            let addr = gen_lval(ap);
+            store(&Type::int_ty(), addr, r);
+            kill(addr);
             r
         }
+        // Every argument takes one word. A struct is passed by its
+        // address, which is how a struct value is represented anyway.
+        NodeType::VaArg(ap) => {
+            let addr = gen_lval(ap);
+            let p = Some(*NUM_REGS.lock().unwrap());
+            *NUM_REGS.lock().unwrap() += 1;
+            let r = Some(*NUM_REGS.lock().unwrap());
+            *NUM_REGS.lock().unwrap() += 1;
+            load(&Type::int_ty(), p, addr);
+            load(&Type::int_ty(), r, p);
+            add(IROp::AddImm, p, Some(1));
+            store(&Type::int_ty(), addr, p);
+            kill(p);
+            kill(addr);
+            r
+        }
+        NodeType::VaEnd(ap) => gen_expr(ap),
         NodeType::Inchar => {
             let r = Some(*NUM_REGS.lock().unwrap());
             *NUM_REGS.lock().unwrap() += 1;
@@ -311,9 +1000,25 @@ fn gen_expr(node: Box<Node>) -> Option<usize> {
             r
         }
         NodeType::Addr(expr) => gen_lval(expr),
+        // Every scalar type but long is one word wide, so a cast only
+        // changes the type of its operand, but for a long, which is cut
+        // to its low word, a char, which is cut to 8 bits, and a _Bool.
+        NodeType::Cast(expr) => {
+            let r = if is_bool(&node.ty) {
+                gen_cond(expr)
+            } else if is_long(&expr.ty) {
+                let (lo, hi) = gen_long(expr);
+                kill(hi);
+                lo
+            } else {
+                gen_expr(expr)
+            };
+            truncate(&node.ty, r);
+            r
+        }
         NodeType::Deref(expr) => {
             let r = gen_expr(expr);
-            load(&node.ty, r, r);
+            load_value(&node.ty, r, r);
             r
         }
         NodeType::StmtExpr(body) => {
@@ -334,7 +1039,18 @@ fn gen_expr(node: Box<Node>) -> Option<usize> {
         }
         NodeType::BinOp(op, lhs, rhs) => {
             use self::TokenType::*;
+            let unsigned = is_unsigned(&lhs.ty) || is_unsigned(&rhs.ty);
             match op {
+                EQ | NE | LeftAngleBracket | LE | RightAngleBracket | GE if is_long(&lhs.ty) => {
+                    gen_long_cmp(op, lhs, rhs)
+                }
+                Equal if is_aggregate(&node.ty) => {
+                    let rhs = gen_expr(rhs);
+                    let lhs = gen_lval(lhs);
+                    gen_copy(node.ty.size, lhs, rhs);
+                    kill(rhs);
+                    lhs
+                }
                 Equal => {
                     let rhs = gen_expr(rhs);
                     let lhs = gen_lval(lhs);
@@ -348,9 +1064,9 @@ fn gen_expr(node: Box<Node>) -> Option<usize> {
                     let x = Some(*NLABEL.lock().unwrap());
                     *NLABEL.lock().unwrap() += 1;
 
-                    let r1 = gen_expr(lhs);
+                    let r1 = gen_cond(lhs);
                     add(IROp::Unless, r1, x);
-                    let r2 = gen_expr(rhs);
+                    let r2 = gen_cond(rhs);
                     add(IROp::Mov, r1, r2);
                     kill(r2);
                     add(IROp::Unless, r1, x);
@@ -364,13 +1080,13 @@ fn gen_expr(node: Box<Node>) -> Option<usize> {
                     let y = Some(*NLABEL.lock().unwrap());
                     *NLABEL.lock().unwrap() += 1;
 
-                    let r1 = gen_expr(lhs);
+                    let r1 = gen_cond(lhs);
                     add(IROp::Unless, r1, x);
                     add(IROp::Imm, r1, Some(1));
                     jmp(y);
                     label(x);
 
-                    let r2 = gen_expr(rhs);
+                    let r2 = gen_cond(rhs);
                     add(IROp::Mov, r1, r2);
                     kill(r2);
                     add(IROp::Unless, r1, y);
@@ -382,18 +1098,20 @@ fn gen_expr(node: Box<Node>) -> Option<usize> {
                 | BitorEQ => gen_assign_op(&op, &node.ty, lhs, rhs),
                 EQ => gen_binop(IROp::EQ, lhs, rhs),
                 NE => gen_binop(IROp::NE, lhs, rhs),
-                LE => gen_binop(IROp::LE, lhs, rhs),
                 And => gen_binop(IROp::AND, lhs, rhs),
                 VerticalBar => gen_binop(IROp::OR, lhs, rhs),
                 Hat => gen_binop(IROp::XOR, lhs, rhs),
                 SHL => gen_binop(IROp::SHL, lhs, rhs),
-                SHR => gen_binop(IROp::SHR, lhs, rhs),
-                Mod => gen_binop(IROp::Mod, lhs, rhs),
+                SHR => {
+                    let op = to_unsigned_op(IROp::SAR, is_unsigned(&lhs.ty));
+                    gen_binop(op, lhs, rhs)
+                }
+                Mod => gen_binop(to_unsigned_op(IROp::Mod, unsigned), lhs, rhs),
                 Comma => {
                     kill(gen_expr(lhs));
                     gen_expr(rhs)
                 }
-                _ => gen_binop(IROp::from(op), lhs, rhs),
+                _ => gen_binop(to_unsigned_op(IROp::from(op), unsigned), lhs, rhs),
             }
         }
         NodeType::Neg(expr) => {
@@ -410,7 +1128,7 @@ fn gen_expr(node: Box<Node>) -> Option<usize> {
             *NLABEL.lock().unwrap() += 1;
             let y = Some(*NLABEL.lock().unwrap());
             *NLABEL.lock().unwrap() += 1;
-            let r = gen_expr(cond);
+            let r = gen_cond(cond);
 
             add(IROp::Unless, r, x);
             let r2 = gen_expr(then);
@@ -426,28 +1144,121 @@ fn gen_expr(node: Box<Node>) -> Option<usize> {
             r
         }
         NodeType::Exclamation(expr) => {
-            let lhs = gen_expr(expr);
-            let rhs = Some(*NUM_REGS.lock().unwrap());
-            *NUM_REGS.lock().unwrap() += 1;
-            add(IROp::Imm, rhs, Some(0));
-            add(IROp::EQ, lhs, rhs);
-            kill(rhs);
-            lhs
+            let r = gen_cond(expr);
+            cmp_zero(IROp::EQ, r);
+            r
         }
         e => unreachable!("{:?}", e),
     }
 }
 
+// Returns the IR label of a user-defined label of the current function.
+fn user_label(name: String) -> Option<usize> {
+    let mut labels = USER_LABELS.lock().unwrap();
+    let l = *labels.entry(name).or_insert_with(|| {
+        let l = *NLABEL.lock().unwrap();
+        *NLABEL.lock().unwrap() += 1;
+        l
+    });
+    Some(l)
+}
+
+// Jumps to the label of the case matching the value in `r`, or to
+// `default_label` if there is none. Dense case values are dispatched
+// through a jump table indexed by `r - min`, sparse ones are compared
+// one by one.
+fn gen_switch_dispatch(r: Option<usize>, cases: &[i32], labels: &[usize], default_label: usize) {
+    if cases.is_empty() {
+        jmp(Some(default_label));
+        return;
+    }
+
+    let min = *cases.iter().min().unwrap();
+    let max = *cases.iter().max().unwrap();
+    let range = (max as i64 - min as i64 + 1) as usize;
+    if cases.len() < JMP_TABLE_MIN_CASES || range > cases.len() * JMP_TABLE_MAX_SPREAD {
+        for (val, l) in cases.iter().zip(labels) {
+            add(IROp::IfEqImm(*val), r, Some(*l));
+        }
+        jmp(Some(default_label));
+        return;
+    }
+
+    let mut table = vec![default_label; range];
+    for (val, l) in cases.iter().zip(labels) {
+        table[(*val as i64 - min as i64) as usize] = *l;
+    }
+
+    if min != 0 {
+        let r2 = Some(*NUM_REGS.lock().unwrap());
+        *NUM_REGS.lock().unwrap() += 1;
+        add(IROp::Imm, r2, Some(min as usize));
+        add(IROp::Sub, r, r2);
+        kill(r2);
+    }
+    add(IROp::JmpTable(default_label, table), r, None);
+}
+
+// Dispatches a long value in `lo` and `hi` on its high word first, then
+// on the low words of the cases sharing it.
+fn gen_long_switch_dispatch(
+    lo: Option<usize>,
+    hi: Option<usize>,
+    cases: &[i32],
+    labels: &[usize],
+    default_label: usize,
+) {
+    let mut groups: Vec<(i32, Vec<i32>, Vec<usize>)> = vec![];
+    for (val, l) in cases.iter().zip(labels) {
+        let (lo_val, hi_val) = (val & 0xFFFF, (val >> 16) & 0xFFFF);
+        match groups.iter_mut().find(|(h, _, _)| *h == hi_val) {
+            Some((_, vals, ls)) => {
+                vals.push(lo_val);
+                ls.push(*l);
+            }
+            None => groups.push((hi_val, vec![lo_val], vec![*l])),
+        }
+    }
+
+    let mut group_labels = vec![];
+    for (hi_val, _, _) in groups.iter() {
+        let l = *NLABEL.lock().unwrap();
+        *NLABEL.lock().unwrap() += 1;
+        add(IROp::IfEqImm(*hi_val), hi, Some(l));
+        group_labels.push(l);
+    }
+    jmp(Some(default_label));
+
+    for ((_, vals, ls), l) in groups.iter().zip(group_labels) {
+        label(Some(l));
+        gen_switch_dispatch(lo, vals, ls, default_label);
+    }
+}
+
 fn gen_stmt(node: Node) {
     match node.op {
         NodeType::Null => return,
         NodeType::Vardef(_, init_may, Scope::Local(offset)) => {
             if let Some(init) = init_may {
+                if is_long(&node.ty) {
+                    let (lo, hi) = gen_long(init);
+                    let lhs = new_reg();
+                    add(IROp::Bprel, lhs, Some(offset));
+                    store_long(lhs, lo, hi);
+                    kill(lhs);
+                    kill(lo);
+                    kill(hi);
+                    return;
+                }
                 let rhs = gen_expr(init);
                 let lhs = Some(*NUM_REGS.lock().unwrap());
                 *NUM_REGS.lock().unwrap() += 1;
                 add(IROp::Bprel, lhs, Some(offset));
-                store(&node.ty, lhs, rhs);
+                if is_struct(&node.ty) {
+                    gen_copy(node.ty.size, lhs, rhs);
+                } else {
+                    store(&node.ty, lhs, rhs);
+                }
                 kill(lhs);
                 kill(rhs);
             }
@@ -459,9 +1270,7 @@ fn gen_stmt(node: Node) {
                 *NLABEL.lock().unwrap() += 1;
                 let y = Some(*NLABEL.lock().unwrap());
                 *NLABEL.lock().unwrap() += 1;
-                let r = gen_expr(cond.clone());
-                add(IROp::Unless, r, x);
-                kill(r);
+                gen_branch(cond, x, false);
                 gen_stmt(*then.clone());
                 jmp(y);
                 label(x);
@@ -472,9 +1281,7 @@ fn gen_stmt(node: Node) {
 
             let x = Some(*NLABEL.lock().unwrap());
             *NLABEL.lock().unwrap() += 1;
-            let r = gen_expr(cond);
-            add(IROp::Unless, r, x);
-            kill(r);
+            gen_branch(cond, x, false);
             gen_stmt(*then);
             label(x);
         }
@@ -486,15 +1293,17 @@ fn gen_stmt(node: Node) {
             let orig = *BREAK_LABEL.lock().unwrap();
             *BREAK_LABEL.lock().unwrap() = *NLABEL.lock().unwrap();
             *NLABEL.lock().unwrap() += 1;
+            let orig_continue = *CONTINUE_LABEL.lock().unwrap();
+            *CONTINUE_LABEL.lock().unwrap() = *NLABEL.lock().unwrap();
+            *NLABEL.lock().unwrap() += 1;
 
             gen_stmt(*init);
             label(x);
             if !cond.is_null() {
-                let r2 = gen_expr(cond);
-                add(IROp::Unless, r2, y);
-                kill(r2);
+                gen_branch(cond, y, false);
             }
             gen_stmt(*body);
+            label(Some(*CONTINUE_LABEL.lock().unwrap()));
             if !inc.is_null() {
                 gen_stmt(*inc);
             }
@@ -502,6 +1311,7 @@ fn gen_stmt(node: Node) {
             label(y);
             label(Some(*BREAK_LABEL.lock().unwrap()));
             *BREAK_LABEL.lock().unwrap() = orig;
+            *CONTINUE_LABEL.lock().unwrap() = orig_continue;
         }
         NodeType::DoWhile(body, cond) => {
             let x = Some(*NLABEL.lock().unwrap());
@@ -509,13 +1319,63 @@ fn gen_stmt(node: Node) {
             let orig = *BREAK_LABEL.lock().unwrap();
             *BREAK_LABEL.lock().unwrap() = *NLABEL.lock().unwrap();
             *NLABEL.lock().unwrap() += 1;
+            let orig_continue = *CONTINUE_LABEL.lock().unwrap();
+            *CONTINUE_LABEL.lock().unwrap() = *NLABEL.lock().unwrap();
+            *NLABEL.lock().unwrap() += 1;
             label(x);
             gen_stmt(*body);
-            let r = gen_expr(cond);
-            add(IROp::If, r, x);
-            kill(r);
+            label(Some(*CONTINUE_LABEL.lock().unwrap()));
+            gen_branch(cond, x, true);
             label(Some(*BREAK_LABEL.lock().unwrap()));
             *BREAK_LABEL.lock().unwrap() = orig;
+            *CONTINUE_LABEL.lock().unwrap() = orig_continue;
+        }
+        NodeType::Switch(cond, body, cases, has_default) => {
+            let orig_break = *BREAK_LABEL.lock().unwrap();
+            *BREAK_LABEL.lock().unwrap() = *NLABEL.lock().unwrap();
+            *NLABEL.lock().unwrap() += 1;
+            let break_label = *BREAK_LABEL.lock().unwrap();
+
+            let mut labels = vec![];
+            for _ in 0..cases.len() {
+                labels.push(*NLABEL.lock().unwrap());
+                *NLABEL.lock().unwrap() += 1;
+            }
+            let default_label = if has_default {
+                let l = *NLABEL.lock().unwrap();
+                *NLABEL.lock().unwrap() += 1;
+                l
+            } else {
+                break_label
+            };
+            let orig_cases = mem::replace(&mut *CASE_LABELS.lock().unwrap(), labels.clone());
+            let orig_default = mem::replace(&mut *DEFAULT_LABEL.lock().unwrap(), default_label);
+
+            if is_long(&cond.ty) {
+                let (lo, hi) = gen_long(cond);
+                gen_long_switch_dispatch(lo, hi, &cases, &labels, default_label);
+                kill(lo);
+                kill(hi);
+            } else {
+                let r = gen_expr(cond);
+                gen_switch_dispatch(r, &cases, &labels, default_label);
+                kill(r);
+            }
+
+            gen_stmt(*body);
+            label(Some(break_label));
+
+            *CASE_LABELS.lock().unwrap() = orig_cases;
+            *DEFAULT_LABEL.lock().unwrap() = orig_default;
+            *BREAK_LABEL.lock().unwrap() = orig_break;
+        }
+        NodeType::Case(idx, stmt) => {
+            label(Some(CASE_LABELS.lock().unwrap()[idx]));
+            gen_stmt(*stmt);
+        }
+        NodeType::Default(stmt) => {
+            label(Some(*DEFAULT_LABEL.lock().unwrap()));
+            gen_stmt(*stmt);
         }
         NodeType::Break => {
             let break_label = *BREAK_LABEL.lock().unwrap();
@@ -524,8 +1384,32 @@ fn gen_stmt(node: Node) {
             }
             jmp(Some(break_label));
         }
+        NodeType::Goto(name) => jmp(user_label(name)),
+        NodeType::Label(name, stmt) => {
+            label(user_label(name));
+            gen_stmt(*stmt);
+        }
+        NodeType::Continue => {
+            let continue_label = *CONTINUE_LABEL.lock().unwrap();
+            if continue_label == 0 {
+                panic!("stray 'continue' statement");
+            }
+            jmp(Some(continue_label));
+        }
         NodeType::Return(expr) => {
-            let r = gen_expr(expr);
+            let r = if is_long(&expr.ty) {
+                let (lo, hi) = gen_long(expr);
+                if *RETURN_LABEL.lock().unwrap() == 0 {
+                    let addr = new_reg();
+                    add(IROp::LabelAddr(LONG_HI.into()), addr, None);
+                    store(&Type::int_ty(), addr, hi);
+                    kill(addr);
+                }
+                kill(hi);
+                lo
+            } else {
+                gen_expr(expr)
+            };
 
             // Statement expression (GNU extension)
             if *RETURN_LABEL.lock().unwrap() != 0 {
@@ -564,15 +1448,46 @@ pub fn gen_ir(nodes: Vec<Node>) -> Vec<Function> {
         match node.op {
             NodeType::Func(name, args, body, stacksize) => {
                 *CODE.lock().unwrap() = vec![];
+                USER_LABELS.lock().unwrap().clear();
                 // *NUM_REGS.lock().unwrap() = 0;
 
-                for (i, arg) in args.iter().enumerate() {
+                // The arguments of a variadic function are all on the
+                // stack, which is where the ones from the seventh on
+                // are in other functions.
+                let first = match node.ty.ty {
+                    Ctype::Func(_, _, true) => REGS_N,
+                    _ => 0,
+                };
+                let words = arg_words(args.iter().map(|arg| &*arg.ty), first);
+                for (arg, word) in args.iter().zip(words) {
                     if let NodeType::Vardef(_, _, Scope::Local(offset)) = arg.op {
-                        store_arg(&arg.ty, Some(offset), Some(i));
+                        if is_long(&arg.ty) {
+                            store_arg(&Type::int_ty(), Some(offset), Some(word));
+                            store_arg(&Type::int_ty(), Some(offset - 1), Some(word + 1));
+                        } else {
+                            store_arg(&arg.ty, Some(offset), Some(word));
+                        }
                     } else {
                         unreachable!();
                     }
                 }
+
+                // A struct argument is passed by its address, which is
+                // stored in the first word of the parameter. The struct
+                // is then copied over it.
+                for arg in args.iter().filter(|arg| is_struct(&arg.ty)) {
+                    if let NodeType::Vardef(_, _, Scope::Local(offset)) = arg.op {
+                        let dst = Some(*NUM_REGS.lock().unwrap());
+                        *NUM_REGS.lock().unwrap() += 1;
+                        let src = Some(*NUM_REGS.lock().unwrap());
+                        *NUM_REGS.lock().unwrap() += 1;
+                        add(IROp::Bprel, dst, Some(offset));
+                        add(IROp::Load(1), src, dst);
+                        gen_copy(arg.ty.size, dst, src);
+                        kill(src);
+                        kill(dst);
+                    }
+                }
                 gen_stmt(*body);
 
                 v.push(Function::new(name, CODE.lock().unwrap().clone(), stacksize));
@@ -19,9 +19,17 @@ impl<'a> From<&'a IROp> for IRInfo {
         use self::IROp::*;
         match op {
             Add => IRInfo::new("ADD", IRType::RegReg),
+            AddC => IRInfo::new("ADDC", IRType::RegReg),
             AddImm => IRInfo::new("ADD", IRType::RegImm),
             Call(_, _, _) => IRInfo::new("CALL", IRType::Call),
+            CallPtr(_, _) => IRInfo::new("CALL", IRType::Call),
+            Save(_) => IRInfo::new("SAVE", IRType::RegList),
+            Restore(_) => IRInfo::new("RESTORE", IRType::RegList),
+            Push => IRInfo::new("PUSH", IRType::Reg),
+            Pop => IRInfo::new("POP", IRType::Reg),
+            PushTarget(_) => IRInfo::new("PUSH_TARGET", IRType::Reg),
             Div => IRInfo::new("DIV", IRType::RegReg),
+            UDiv => IRInfo::new("UDIV", IRType::RegReg),
             Imm => IRInfo::new("MOV", IRType::RegImm),
             Jmp => IRInfo::new("JMP", IRType::Jmp),
             Kill => IRInfo::new("KILL", IRType::Reg),
@@ -31,13 +39,23 @@ impl<'a> From<&'a IROp> for IRInfo {
             NE => IRInfo::new("NE", IRType::RegReg),
             LE => IRInfo::new("LE", IRType::RegReg),
             LT => IRInfo::new("LT", IRType::RegReg),
+            GE => IRInfo::new("GE", IRType::RegReg),
+            GT => IRInfo::new("GT", IRType::RegReg),
+            ULE => IRInfo::new("ULE", IRType::RegReg),
+            ULT => IRInfo::new("ULT", IRType::RegReg),
+            UGE => IRInfo::new("UGE", IRType::RegReg),
+            UGT => IRInfo::new("UGT", IRType::RegReg),
             AND => IRInfo::new("AND", IRType::RegReg),
             OR => IRInfo::new("OR", IRType::RegReg),
             XOR => IRInfo::new("XOR", IRType::RegReg),
             SHL => IRInfo::new("SHL", IRType::RegReg),
             SHR => IRInfo::new("SHR", IRType::RegReg),
+            SAR => IRInfo::new("SAR", IRType::RegReg),
             Mod => IRInfo::new("MOD", IRType::RegReg),
+            UMod => IRInfo::new("UMOD", IRType::RegReg),
             Neg => IRInfo::new("NEG", IRType::Reg),
+            Sext8 => IRInfo::new("SEXT8", IRType::Reg),
+            Zext8 => IRInfo::new("ZEXT8", IRType::Reg),
             Load(_) => IRInfo::new("LOAD", IRType::Mem),
             Mov => IRInfo::new("MOV", IRType::RegReg),
             Mul => IRInfo::new("MUL", IRType::RegReg),
@@ -51,8 +69,12 @@ impl<'a> From<&'a IROp> for IRInfo {
             Sub => IRInfo::new("SUB", IRType::RegReg),
             SubImm => IRInfo::new("SUB", IRType::RegImm),
             Bprel => IRInfo::new("BPREL", IRType::RegImm),
+            ArgAddr => IRInfo::new("ARG_ADDR", IRType::RegImm),
             If => IRInfo::new("IF", IRType::RegLabel),
             Unless => IRInfo::new("UNLESS", IRType::RegLabel),
+            IfEqImm(_) => IRInfo::new("IF_EQ", IRType::RegLabel),
+            IfCmp(_, _) => IRInfo::new("IF_CMP", IRType::RegReg),
+            JmpTable(_, _) => IRInfo::new("JMP_TABLE", IRType::JmpTable),
         }
     }
 }
@@ -63,7 +85,7 @@ impl fmt::Display for IR {
 
         let info = &IRInfo::from(&self.op);
 
-        let lhs = self.lhs.unwrap();
+        let lhs = self.lhs.unwrap_or(0);
         match info.ty {
             Label => write!(f, ".L{}:", lhs),
             LabelAddr => match self.op {
@@ -73,7 +95,14 @@ impl fmt::Display for IR {
             Imm => write!(f, "  {} {}", info.name, lhs),
             Reg => write!(f, "  {} r{}", info.name, lhs),
             Jmp => write!(f, "  {} .L{}", info.name, lhs),
-            RegReg => write!(f, "  {} r{}, r{}", info.name, lhs, self.rhs.unwrap()),
+            RegReg => match self.op {
+                IROp::IfCmp(ref cmp, label) => {
+                    let cmp = IRInfo::from(&**cmp).name;
+                    let rhs = self.rhs.unwrap();
+                    write!(f, "  {} {} r{}, r{}, .L{}", info.name, cmp, lhs, rhs, label)
+                }
+                _ => write!(f, "  {} r{}, r{}", info.name, lhs, self.rhs.unwrap()),
+            },
             Mem | StoreArg => match self.op {
                 IROp::Load(ref size) | IROp::Store(ref size) => {
                     write!(f, "  {}{} r{}, {}", info.name, size, lhs, self.rhs.unwrap())
@@ -84,11 +113,37 @@ impl fmt::Display for IR {
                 _ => unreachable!(),
             },
             RegImm => write!(f, "  {} r{}, {}", info.name, lhs, self.rhs.unwrap() as i32),
-            RegLabel => write!(f, "  {} r{}, .L{}", info.name, lhs, self.rhs.unwrap()),
+            RegLabel => match self.op {
+                IROp::IfEqImm(val) => write!(
+                    f,
+                    "  {} r{}, {}, .L{}",
+                    info.name,
+                    lhs,
+                    val,
+                    self.rhs.unwrap()
+                ),
+                _ => write!(f, "  {} r{}, .L{}", info.name, lhs, self.rhs.unwrap()),
+            },
+            JmpTable => match self.op {
+                IROp::JmpTable(default, ref labels) => {
+                    let mut sb: String = format!("  {} r{}, [", info.name, lhs);
+                    for (i, l) in labels.iter().enumerate() {
+                        if i != 0 {
+                            sb.push_str(", ");
+                        }
+                        sb.push_str(&format!(".L{}", l));
+                    }
+                    write!(f, "{}], .L{}", sb, default)
+                }
+                _ => unreachable!(),
+            },
             Call => match self.op {
-                IROp::Call(ref name, nargs, args) => {
-                    let mut sb: String = format!("  r{} = {}(", lhs, name);
-                    for (i, arg) in args.iter().enumerate().take(nargs) {
+                IROp::Call(_, ref args, _) | IROp::CallPtr(ref args, _) => {
+                    let mut sb: String = match self.op {
+                        IROp::Call(ref name, _, _) => format!("  r{} = {}(", lhs, name),
+                        _ => format!("  r{} = *(", lhs),
+                    };
+                    for (i, arg) in args.iter().enumerate() {
                         if i != 0 {
                             sb.push_str(&", ".to_string());
                         }
@@ -99,6 +154,13 @@ impl fmt::Display for IR {
                 }
                 _ => unreachable!(),
             },
+            RegList => match self.op {
+                IROp::Save(ref regs) | IROp::Restore(ref regs) => {
+                    let regs: Vec<String> = regs.iter().map(|r| format!("r{}", r)).collect();
+                    write!(f, "  {} {}", info.name, regs.join(", "))
+                }
+                _ => unreachable!(),
+            },
             Noarg => write!(f, "  {}", info.name),
         }
     }
@@ -11,7 +11,15 @@ mod util;
 #[macro_use]
 extern crate lazy_static;
 
-const REGS_N: usize = 7;
+use std::sync::Mutex;
+
+const REGS_N: usize = 6;
+
+lazy_static! {
+    // When set, char is a 16-bit integer like int, as it was in
+    // earlier versions of the compiler.
+    pub static ref LEGACY_CHAR: Mutex<bool> = Mutex::new(false);
+}
 
 #[macro_export]
 macro_rules! matches(
@@ -27,17 +35,29 @@ macro_rules! matches(
 #[derive(Debug, PartialEq, Clone)]
 pub enum TokenType {
     Num(i32),            // Number literal
+    UNum(i32),           // Number literal of type unsigned int
+    LongNum(i32, bool),  // Number literal of type long. (val, is_unsigned)
     Str(String, usize),  // String literal. (str, len)
     CharLiteral(String), // Char literal.
     Ident(String),       // Identifier
     Param(usize),        // Function-like macro parameter
     Arrow,               // ->
+    Ellipsis,            // ...
     Extern,              // "extern"
+    Static,              // "static"
     Typedef,             // "typedef"
     Int,                 // "int"
     Char,                // "char"
+    Bool,                // "_Bool"
+    Long,                // "long"
+    Signed,              // "signed"
+    Unsigned,            // "unsigned"
     Void,                // "void"
     Struct,              // "struct"
+    Union,               // "union"
+    Enum,                // "enum"
+    Const,               // "const"
+    Volatile,            // "volatile"
     Plus,                // +
     Minus,               // -
     Mul,                 // *
@@ -51,12 +71,18 @@ pub enum TokenType {
     Hat,                 // ^
     Colon,               // :
     HashMark,            // #
+    HashHash,            // ##
     If,                  // "if"
     Else,                // "else"
     For,                 // "for"
     Do,                  // "do"
     While,               // "while"
     Break,               // "break"
+    Continue,            // "continue"
+    Goto,                // "goto"
+    Switch,              // "switch"
+    Case,                // "case"
+    Default,             // "default"
     EQ,                  // ==
     NE,                  // !=
     LE,                  // <=
@@ -93,7 +119,12 @@ pub enum TokenType {
     Inchar,              // "inchar"
     Sizeof,              // "sizeof"
     Alignof,             // "_Alignof"
+    VaStart,             // "__builtin_va_start"
+    VaArg,               // "__builtin_va_arg"
+    VaEnd,               // "__builtin_va_end"
     NewLine,             // preprocessor-only token
+    // Scanning error, reported unless in a skipped group
+    Invalid(&'static str),
 }
 
 // Character Kind
@@ -144,11 +175,13 @@ impl TokenType {
 pub enum Ctype {
     Int,
     Char,
+    Bool,
+    Long,
     Void,
-    Ptr(Box<Type>),           // ptr of
-    Ary(Box<Type>, usize),    // ary of, len
-    Struct(Vec<parse::Node>), // members
-    Func(Box<Type>),
+    Ptr(Box<Type>),                        // ptr of
+    Ary(Box<Type>, usize),                 // ary of, len
+    Struct(Vec<parse::Node>, bool, usize), // members, is union, id
+    Func(Box<Type>, Vec<Type>, bool),      // returning, parameters, is variadic
 }
 
 impl Default for Ctype {
@@ -162,6 +195,9 @@ pub struct Type {
     pub ty: Ctype,
     pub size: usize,  // sizeof
     pub align: usize, // alignof
+    pub is_unsigned: bool,
+    pub is_const: bool,
+    pub is_volatile: bool,
 }
 
 impl Default for Type {
@@ -170,6 +206,9 @@ impl Default for Type {
             ty: Ctype::default(),
             size: 1,
             align: 1,
+            is_unsigned: false,
+            is_const: false,
+            is_volatile: false,
         }
     }
 }
@@ -180,16 +219,29 @@ pub enum Scope {
     Global(String, usize, bool), // data, len, is_extern
 }
 
+// Initial value of a word of a global variable
+#[derive(Debug, Clone)]
+pub enum Data {
+    Num(i32),          // Number
+    Addr(String, i32), // Address of a global variable, (name, offset)
+}
+
 #[derive(Debug, Clone)]
 pub struct Var {
     ty: Box<Type>,
     pub name: String,
     pub scope: Scope,
+    pub init: Vec<Data>, // One per word, empty if not initialized
 }
 
 impl Var {
     fn new(ty: Box<Type>, name: String, scope: Scope) -> Self {
-        Var { ty, name, scope }
+        Var {
+            ty,
+            name,
+            scope,
+            init: vec![],
+        }
     }
 
     fn new_global(ty: Box<Type>, name: String, data: String, len: usize, is_extern: bool) -> Self {
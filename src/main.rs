@@ -8,6 +8,7 @@ use icmc_cc::preprocess::Preprocessor;
 use icmc_cc::regalloc::alloc_regs;
 use icmc_cc::sema::sema;
 use icmc_cc::token::tokenize;
+use icmc_cc::LEGACY_CHAR;
 
 use std::fs;
 use std::io::{stdin, stdout, Read, Write};
@@ -15,7 +16,7 @@ use std::io::{stdin, stdout, Read, Write};
 use structopt::StructOpt;
 
 fn fs_read(path: &str) -> Option<String> {
-    Some(fs::read_to_string(path).unwrap())
+    fs::read_to_string(path).ok()
 }
 
 #[derive(StructOpt, Debug)]
@@ -25,10 +26,24 @@ struct Opt {
     fin: Option<String>,
     #[structopt(short = "-o", long = "--output")]
     fout: Option<String>,
+    /// Treat char as a 16-bit integer, like int
+    #[structopt(long = "--legacy-char")]
+    legacy_char: bool,
+    /// Add a directory to the include search path
+    #[structopt(short = "-I", number_of_values = 1)]
+    include_dirs: Vec<String>,
+    /// Define a macro, as NAME or NAME=value
+    #[structopt(short = "-D", number_of_values = 1)]
+    defines: Vec<String>,
+    /// Undefine a macro
+    #[structopt(short = "-U", number_of_values = 1)]
+    undefs: Vec<String>,
 }
 
 fn main() {
-    let opt = Opt::from_args();
+    let matches = Opt::clap().get_matches();
+    let opt = Opt::from_clap(&matches);
+    *LEGACY_CHAR.lock().unwrap() = opt.legacy_char;
 
     let (input_data, input_file) = match opt.fin {
         Some(path) => (fs_read(path.as_str()).unwrap(), path),
@@ -39,11 +54,29 @@ fn main() {
         }
     };
 
-    let tokens = tokenize(
-        input_data,
-        input_file,
-        &mut Preprocessor::new(Box::new(fs_read)),
-    );
+    let mut ctx = Preprocessor::new(Box::new(fs_read));
+    for dir in opt.include_dirs {
+        ctx.add_include_dir(dir);
+    }
+
+    // -D and -U are applied in the order they are given.
+    let mut macro_opts = vec![];
+    if let Some(indices) = matches.indices_of("defines") {
+        macro_opts.extend(indices.zip(opt.defines.iter().map(|def| (def, true))));
+    }
+    if let Some(indices) = matches.indices_of("undefs") {
+        macro_opts.extend(indices.zip(opt.undefs.iter().map(|name| (name, false))));
+    }
+    macro_opts.sort_by_key(|(i, _)| *i);
+    for (_, (arg, is_define)) in macro_opts {
+        if is_define {
+            ctx.define_macro(arg);
+        } else {
+            ctx.undef_macro(arg);
+        }
+    }
+
+    let tokens = tokenize(input_data, input_file, &mut ctx);
 
     let nodes = parse(&tokens);
     let (nodes, globals) = sema(nodes);
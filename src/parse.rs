@@ -1,8 +1,9 @@
 use crate::token::Token;
 use crate::util::roundup;
-use crate::{Ctype, Scope, TokenType, Type};
+use crate::{Ctype, Scope, TokenType, Type, LEGACY_CHAR};
 
-use std::collections::HashMap;
+use std::cmp::max;
+use std::collections::{HashMap, HashSet};
 
 // Quoted from 9cc
 // > This is a recursive-descendent parser which constructs abstract
@@ -39,6 +40,7 @@ pub fn parse(tokens: &Vec<Token>) -> Vec<Node> {
 struct Env {
     tags: HashMap<String, Type>,
     typedefs: HashMap<String, Type>,
+    vars: HashMap<String, Option<i32>>, // ordinary identifiers and the values of enumerators
     next: Option<Box<Env>>,
 }
 
@@ -48,6 +50,7 @@ impl Env {
             next,
             tags: HashMap::new(),
             typedefs: HashMap::new(),
+            vars: HashMap::new(),
         }
     }
 }
@@ -71,12 +74,19 @@ pub enum NodeType {
     If(Box<Node>, Box<Node>, Option<Box<Node>>),     // "if" ( cond ) then "else" els
     Ternary(Box<Node>, Box<Node>, Box<Node>),        // cond ? then : els
     For(Box<Node>, Box<Node>, Box<Node>, Box<Node>), // "for" ( init; cond; inc ) body
+    Switch(Box<Node>, Box<Node>, Vec<i32>, bool),    // "switch" ( cond ) body, cases, has default
     Break,
+    Continue,
+    Goto(String),
+    Label(String, Box<Node>),
     DoWhile(Box<Node>, Box<Node>), // do { body } while(cond)
+    Case(usize, Box<Node>),        // "case" label, (index into the switch case values, stmt)
+    Default(Box<Node>),            // "default" label, stmt
     Addr(Box<Node>),               // address-of operator("&"), expr
     Deref(Box<Node>),              // pointer dereference ("*"), expr
     Dot(Box<Node>, String, usize), // Struct member accessm, (expr, name, offset)
     Exclamation(Box<Node>),        // !, expr
+    Cast(Box<Node>),               // (type) expr, to the type of the node
     Neg(Box<Node>),                // -
     PostInc(Box<Node>),            // post ++
     PostDec(Box<Node>),            // post --
@@ -85,10 +95,19 @@ pub enum NodeType {
     Inchar,                        // Inchar
     Sizeof(Box<Node>),             // "sizeof", expr
     Alignof(Box<Node>),            // "_Alignof", expr
-    Call(String, Vec<Node>),       // Function call(name, args)
+    VaStart(Box<Node>, usize),     // va_start, (va_list, number of named parameters)
+    VaArg(Box<Node>),              // va_arg, va_list, to the type of the node
+    VaEnd(Box<Node>),              // va_end, va_list
+    Call(String, Vec<Node>, bool), // Function call(name, args, is variadic)
+    CallPtr(Box<Node>, Vec<Node>), // Call through a pointer to function, (expr, args)
     Func(String, Vec<Node>, Box<Node>, usize), // Function definition(name, args, body, stacksize)
+    Static(Box<Node>),             // "static" definition or declaration
     CompStmt(Vec<Node>),           // Compound statement
-    VecStmt(Vec<Node>),            // For the purpose of assign a value when initializing an array.
+    VecStmt(Vec<Node>),            // Statements in the current scope, such as an initialized local
+    InitList(Vec<Node>),           // Brace-enclosed initializer list
+    MemberDesig(String, Box<Node>), // ".name = init" in an initializer list
+    IndexDesig(usize, Box<Node>),  // "[index] = init" in an initializer list
+    CompLit(Box<Node>),            // Compound literal, (type) { init }, to the type of the node
     ExprStmt(Box<Node>),           // Expression statement
     StmtExpr(Box<Node>),           // Statement expression (GNU extn.)
     Null,
@@ -100,6 +119,35 @@ pub struct Node {
     pub ty: Box<Type>, // C type
 }
 
+// Converts a folded value to its type. An int that overflows is left
+// as is, as it is undefined.
+fn wrap(val: i64, is_long: bool, is_unsigned: bool) -> i64 {
+    match (is_long, is_unsigned) {
+        (true, true) => val as u32 as i64,
+        (false, true) => val as u16 as i64,
+        _ => val as i32 as i64,
+    }
+}
+
+// The type of the usual arithmetic conversions of two folded operands,
+// as whether it is a long and whether it is unsigned.
+fn common_type(
+    lhs_long: bool,
+    lhs_unsigned: bool,
+    rhs_long: bool,
+    rhs_unsigned: bool,
+) -> (bool, bool) {
+    if lhs_long || rhs_long {
+        // An unsigned int fits in a long.
+        (
+            true,
+            (lhs_long && lhs_unsigned) || (rhs_long && rhs_unsigned),
+        )
+    } else {
+        (false, lhs_unsigned || rhs_unsigned)
+    }
+}
+
 impl Node {
     pub fn new(op: NodeType) -> Self {
         Self {
@@ -129,6 +177,97 @@ impl Node {
         Node::new(NodeType::Num(val))
     }
 
+    // Folds an integer constant expression, such as a case label.
+    pub fn eval(&self) -> Option<i32> {
+        self.eval_typed().map(|(val, _, _)| val as i32)
+    }
+
+    // Folds an integer constant expression along with whether its type
+    // is a long and whether it is unsigned. The operators are not typed
+    // before sema, so their types are derived from those of the operands.
+    fn eval_typed(&self) -> Option<(i64, bool, bool)> {
+        use self::TokenType::*;
+        match self.op {
+            NodeType::Num(val) => {
+                let (is_long, is_unsigned) =
+                    (matches!(self.ty.ty, Ctype::Long), self.ty.is_unsigned);
+                Some((wrap(val as i64, is_long, is_unsigned), is_long, is_unsigned))
+            }
+            NodeType::Neg(ref expr) => {
+                let (val, is_long, is_unsigned) = expr.eval_typed()?;
+                Some((wrap(-val, is_long, is_unsigned), is_long, is_unsigned))
+            }
+            NodeType::Exclamation(ref expr) => {
+                let (val, _, _) = expr.eval_typed()?;
+                Some(((val == 0) as i64, false, false))
+            }
+            NodeType::Cast(ref expr) => {
+                let (val, _, _) = expr.eval_typed()?;
+                let is_unsigned = self.ty.is_unsigned;
+                let val = match self.ty.ty {
+                    Ctype::Long => return Some((wrap(val, true, is_unsigned), true, is_unsigned)),
+                    Ctype::Bool => (val != 0) as i64,
+                    _ if self.ty.is_char() && is_unsigned => val as u8 as i64,
+                    _ if self.ty.is_char() => val as i8 as i64,
+                    _ if is_unsigned => val as u16 as i64,
+                    _ => val as i16 as i64,
+                };
+                // A char or a _Bool is promoted to int.
+                Some((val, false, is_unsigned && !self.ty.is_char()))
+            }
+            NodeType::Ternary(ref cond, ref then, ref els) => {
+                let (cond, _, _) = cond.eval_typed()?;
+                let (then, then_long, then_unsigned) = then.eval_typed()?;
+                let (els, els_long, els_unsigned) = els.eval_typed()?;
+                let (is_long, is_unsigned) =
+                    common_type(then_long, then_unsigned, els_long, els_unsigned);
+                let val = if cond != 0 { then } else { els };
+                Some((wrap(val, is_long, is_unsigned), is_long, is_unsigned))
+            }
+            NodeType::BinOp(ref op, ref lhs, ref rhs) => {
+                let (lhs, lhs_long, lhs_unsigned) = lhs.eval_typed()?;
+                let (rhs, rhs_long, rhs_unsigned) = rhs.eval_typed()?;
+
+                // The type of a shift is the one of its left operand.
+                if *op == SHL || *op == SHR {
+                    let count = rhs as u32;
+                    let val = match op {
+                        SHL => lhs.wrapping_shl(count),
+                        _ => lhs.wrapping_shr(count),
+                    };
+                    return Some((wrap(val, lhs_long, lhs_unsigned), lhs_long, lhs_unsigned));
+                }
+
+                let (is_long, is_unsigned) =
+                    common_type(lhs_long, lhs_unsigned, rhs_long, rhs_unsigned);
+                let lhs = wrap(lhs, is_long, is_unsigned);
+                let rhs = wrap(rhs, is_long, is_unsigned);
+                let val = match op {
+                    Plus => lhs + rhs,
+                    Minus => lhs - rhs,
+                    Mul => lhs * rhs,
+                    Div if rhs != 0 => lhs / rhs,
+                    Mod if rhs != 0 => lhs % rhs,
+                    And => lhs & rhs,
+                    VerticalBar => lhs | rhs,
+                    Hat => lhs ^ rhs,
+                    EQ => return Some(((lhs == rhs) as i64, false, false)),
+                    NE => return Some(((lhs != rhs) as i64, false, false)),
+                    LeftAngleBracket => return Some(((lhs < rhs) as i64, false, false)),
+                    LE => return Some(((lhs <= rhs) as i64, false, false)),
+                    RightAngleBracket => return Some(((lhs > rhs) as i64, false, false)),
+                    GE => return Some(((lhs >= rhs) as i64, false, false)),
+                    Logand => return Some(((lhs != 0 && rhs != 0) as i64, false, false)),
+                    Logor => return Some(((lhs != 0 || rhs != 0) as i64, false, false)),
+                    Comma => return Some((rhs, rhs_long, rhs_unsigned)),
+                    _ => return None,
+                };
+                Some((wrap(val, is_long, is_unsigned), is_long, is_unsigned))
+            }
+            _ => None,
+        }
+    }
+
     pub fn is_null(&self) -> bool {
         match self.op {
             NodeType::Null => true,
@@ -143,6 +282,9 @@ impl Type {
             ty,
             size,
             align: size,
+            is_unsigned: false,
+            is_const: false,
+            is_volatile: false,
         }
     }
 
@@ -158,6 +300,26 @@ impl Type {
         Type::new(Ctype::Int, 1)
     }
 
+    // A _Bool holds either 0 or 1.
+    pub fn bool_ty() -> Self {
+        let mut ty = Type::new(Ctype::Bool, 1);
+        ty.is_unsigned = true;
+        ty
+    }
+
+    // A char takes a word like an int, but holds an 8-bit value,
+    // unless the legacy behavior is asked for.
+    pub fn is_char(&self) -> bool {
+        matches!(self.ty, Ctype::Char) && !*LEGACY_CHAR.lock().unwrap()
+    }
+
+    // A long takes two words, the low one first.
+    pub fn long_ty() -> Self {
+        let mut ty = Type::new(Ctype::Long, 2);
+        ty.align = 1;
+        ty
+    }
+
     pub fn ptr_to(base: Box<Type>) -> Self {
         Type::new(Ctype::Ptr(base), 1)
     }
@@ -175,6 +337,10 @@ pub struct Parser<'a> {
     tokens: &'a Vec<Token>,
     pos: usize,
     env: Env,
+    switches: Vec<(Vec<i32>, bool)>, // case values and default of the enclosing switches
+    labels: HashSet<String>,         // labels defined in the current function
+    gotos: Vec<usize>,               // positions of the goto targets in the current function
+    structs: usize,                  // struct and union types declared so far
 }
 
 impl<'a> Parser<'a> {
@@ -183,6 +349,10 @@ impl<'a> Parser<'a> {
             tokens,
             pos: 0,
             env: Env::new(None),
+            switches: vec![],
+            labels: HashSet::new(),
+            gotos: vec![],
+            structs: 0,
         }
     }
 
@@ -216,6 +386,22 @@ impl<'a> Parser<'a> {
         }
     }
 
+    // Returns the value of the enumerator the name refers to, unless
+    // it is shadowed by a variable, function or typedef name.
+    fn find_enum(&self, name: &str) -> Option<i32> {
+        let mut next: &Option<Box<Env>> = &Some(Box::new(self.env.clone()));
+        loop {
+            if let Some(ref e) = next {
+                if let Some(val) = e.vars.get(name) {
+                    return *val;
+                }
+                next = &e.next;
+            } else {
+                return None;
+            }
+        }
+    }
+
     fn expect(&mut self, ty: TokenType) {
         let t = &self.tokens[self.pos];
         if t.ty != ty {
@@ -238,18 +424,39 @@ impl<'a> Parser<'a> {
         if let TokenType::Ident(ref name) = t.ty {
             return self.find_typedef(name).is_some();
         }
-        t.ty == Int || t.ty == Char || t.ty == Void || t.ty == Struct
+        t.ty == Int
+            || t.ty == Char
+            || t.ty == Bool
+            || t.ty == Long
+            || t.ty == Signed
+            || t.ty == Unsigned
+            || t.ty == Void
+            || t.ty == Struct
+            || t.ty == Union
+            || t.ty == Enum
+            || t.ty == Const
+            || t.ty == Volatile
+            || t.ty == Static
+            || t.ty == Extern
+            || t.ty == Typedef
     }
 
-    fn set_offset(members: &mut Vec<Node>) -> (usize, usize) {
+    // Members of a union all start at offset 0 and the union is as
+    // large as its largest member.
+    fn set_offset(members: &mut Vec<Node>, is_union: bool) -> (usize, usize) {
         let mut off = 0;
         let mut align = 0;
         for node in members {
             if let NodeType::Vardef(_, _, Scope::Local(offset)) = &mut node.op {
                 let t = &node.ty;
-                off = roundup(off, t.align);
-                *offset = off;
-                off += t.size;
+                if is_union {
+                    *offset = 0;
+                    off = max(off, t.size);
+                } else {
+                    off = roundup(off, t.align);
+                    *offset = off;
+                    off += t.size;
+                }
 
                 if align < t.align {
                     align = t.align;
@@ -262,14 +469,90 @@ impl<'a> Parser<'a> {
     }
 
     fn add_member(ty: &mut Type, mut members: Vec<Node>) {
-        let (off, align) = Self::set_offset(&mut members);
-        if let Ctype::Struct(ref mut members2) = ty.ty {
+        if let Ctype::Struct(ref mut members2, is_union, _) = ty.ty {
+            let (off, align) = Self::set_offset(&mut members, is_union);
             *members2 = members;
+            ty.size = roundup(off, align);
+            ty.align = align;
+        }
+    }
+
+    // Reads type qualifiers and applies them to `ty`.
+    fn qualifiers(&mut self, ty: &mut Type) {
+        loop {
+            if self.consume(TokenType::Const) {
+                ty.is_const = true;
+            } else if self.consume(TokenType::Volatile) {
+                ty.is_volatile = true;
+            } else {
+                return;
+            }
         }
-        ty.size = roundup(off, align);
     }
 
+    // Reads the specifiers of a declaration where no storage class
+    // may appear, such as a parameter or a type name.
     fn decl_specifiers(&mut self) -> Option<Type> {
+        let (ty, storage) = self.specifiers()?;
+        if let Some(t) = storage {
+            t.bad_token("storage class not allowed here");
+        }
+        Some(ty)
+    }
+
+    // Specifiers, qualifiers and the storage class may come in any
+    // order, as in `const int`, `int const` or `long static unsigned`.
+    fn specifiers(&mut self) -> Option<(Type, Option<&'a Token>)> {
+        use self::TokenType::*;
+        let mut quals = Type::default();
+        let mut words = vec![];
+        let mut ty = None;
+        let mut storage = None;
+        loop {
+            self.qualifiers(&mut quals);
+            let t = &self.tokens[self.pos];
+            match t.ty {
+                Static | Extern | Typedef => {
+                    if storage.is_some() {
+                        t.bad_token("multiple storage classes in declaration");
+                    }
+                    self.pos += 1;
+                    storage = Some(t);
+                }
+                Int | Char | Bool | Long | Signed | Unsigned | Void if ty.is_none() => {
+                    self.pos += 1;
+                    words.push(t);
+                }
+                _ if ty.is_none() && words.is_empty() => ty = Some(self.type_specifier()?),
+                _ => break,
+            }
+        }
+        let mut ty = ty.unwrap_or_else(|| Self::keyword_type(&words));
+        ty.is_const |= quals.is_const;
+        ty.is_volatile |= quals.is_volatile;
+        Some((ty, storage))
+    }
+
+    // Resolves the keywords of a basic type, such as `unsigned long
+    // int`, to the type. `long long` is a long.
+    fn keyword_type(words: &[&Token]) -> Type {
+        use self::TokenType::*;
+        let n = |ty: TokenType| words.iter().filter(|t| t.ty == ty).count();
+        let sign = n(Signed) + n(Unsigned);
+        let base = (n(Void), n(Bool), n(Char), n(Int), n(Long));
+        let mut ty = match base {
+            (1, 0, 0, 0, 0) if sign == 0 => Type::void_ty(),
+            (0, 1, 0, 0, 0) if sign == 0 => Type::bool_ty(),
+            (0, 0, 1, 0, 0) if sign <= 1 => Type::char_ty(),
+            (0, 0, 0, 0 | 1, 0) if sign <= 1 => Type::int_ty(),
+            (0, 0, 0, 0 | 1, 1 | 2) if sign <= 1 => Type::long_ty(),
+            _ => words[0].bad_token("invalid combination of type specifiers"),
+        };
+        ty.is_unsigned |= n(Unsigned) == 1;
+        ty
+    }
+
+    fn type_specifier(&mut self) -> Option<Type> {
         let t = &self.tokens[self.pos];
         self.pos += 1;
         match t.ty {
@@ -281,10 +564,8 @@ impl<'a> Parser<'a> {
                     return None;
                 }
             }
-            TokenType::Int => Some(Type::int_ty()),
-            TokenType::Char => Some(Type::char_ty()),
-            TokenType::Void => Some(Type::void_ty()),
-            TokenType::Struct => {
+            TokenType::Struct | TokenType::Union => {
+                let is_union = t.ty == TokenType::Union;
                 let mut tag_may: Option<String> = None;
                 let t = &self.tokens[self.pos];
                 if let TokenType::Ident(ref name) = t.ty {
@@ -305,7 +586,10 @@ impl<'a> Parser<'a> {
                         ty_may = self.find_tag(&tag);
                     }
                 }
-                let mut ty = ty_may.unwrap_or(Type::new(Ctype::Struct(vec![]), 10));
+                let mut ty = ty_may.unwrap_or_else(|| {
+                    self.structs += 1;
+                    Type::new(Ctype::Struct(vec![], is_union, self.structs), 10)
+                });
 
                 if !members.is_empty() {
                     Self::add_member(&mut ty, members);
@@ -315,6 +599,42 @@ impl<'a> Parser<'a> {
                 }
                 Some(ty.clone())
             }
+            TokenType::Enum => {
+                let mut tag_may: Option<String> = None;
+                let t = &self.tokens[self.pos];
+                if let TokenType::Ident(ref name) = t.ty {
+                    self.pos += 1;
+                    tag_may = Some(name.clone())
+                }
+
+                if self.consume(TokenType::LeftBrace) {
+                    let mut val = 0;
+                    while !self.consume(TokenType::RightBrace) {
+                        let name = self.ident();
+                        if self.consume(TokenType::Equal) {
+                            val = self.const_expr();
+                        }
+                        self.env.vars.insert(name, Some(val));
+                        val += 1;
+
+                        if !self.consume(TokenType::Comma) {
+                            self.expect(TokenType::RightBrace);
+                            break;
+                        }
+                    }
+
+                    if let Some(tag) = tag_may {
+                        self.env.tags.insert(tag, Type::int_ty());
+                    }
+                } else if let Some(ref tag) = tag_may {
+                    if self.find_tag(tag).is_none() {
+                        t.bad_token(&format!("unknown enum: {}", tag));
+                    }
+                }
+
+                // Enumerations are compatible with int.
+                Some(Type::int_ty())
+            }
             _ => t.bad_token("typename expected"),
         }
     }
@@ -334,6 +654,17 @@ impl<'a> Parser<'a> {
         self.pos += 1;
         match t.ty {
             TokenType::Num(val) => Node::new_num(val),
+            TokenType::UNum(val) => {
+                let mut node = Node::new_num(val);
+                node.ty.is_unsigned = true;
+                node
+            }
+            TokenType::LongNum(val, is_unsigned) => {
+                let mut node = Node::new_num(val);
+                node.ty = Box::new(Type::long_ty());
+                node.ty.is_unsigned = is_unsigned;
+                node
+            }
             TokenType::Str(ref str, len) => {
                 let mut node = Node::new(NodeType::Str(str.clone(), len));
                 node.ty = Box::new(Type::ary_of(Box::new(Type::char_ty()), len));
@@ -341,20 +672,13 @@ impl<'a> Parser<'a> {
             }
             TokenType::Ident(ref name) => {
                 if !self.consume(TokenType::LeftParen) {
+                    if let Some(val) = self.find_enum(name) {
+                        return Node::new_num(val);
+                    }
                     return Node::new(NodeType::Ident(name.clone()));
                 }
 
-                let mut args: Vec<Node> = vec![];
-                if self.consume(TokenType::RightParen) {
-                    return Node::new(NodeType::Call(name.clone(), args));
-                }
-
-                args.push(self.assign());
-                while self.consume(TokenType::Comma) {
-                    args.push(self.assign());
-                }
-                self.expect(TokenType::RightParen);
-                Node::new(NodeType::Call(name.clone(), args))
+                Node::new(NodeType::Call(name.clone(), self.call_args(), false))
             }
             TokenType::LeftParen => {
                 if self.consume(TokenType::LeftBrace) {
@@ -371,13 +695,57 @@ impl<'a> Parser<'a> {
                 self.expect(TokenType::RightParen);
                 Node::new(NodeType::Inchar)
             }
+            TokenType::VaStart => {
+                // The last named parameter is only there for
+                // compatibility, the number of named parameters is known.
+                self.expect(TokenType::LeftParen);
+                let ap = self.assign();
+                self.expect(TokenType::Comma);
+                self.assign();
+                self.expect(TokenType::RightParen);
+                Node::new(NodeType::VaStart(Box::new(ap), 0))
+            }
+            TokenType::VaArg => {
+                self.expect(TokenType::LeftParen);
+                let ap = self.assign();
+                self.expect(TokenType::Comma);
+                let ty = self.type_name();
+                self.expect(TokenType::RightParen);
+                let mut node = Node::new(NodeType::VaArg(Box::new(ap)));
+                node.ty = Box::new(ty);
+                node
+            }
+            TokenType::VaEnd => {
+                self.expect(TokenType::LeftParen);
+                let ap = self.assign();
+                self.expect(TokenType::RightParen);
+                Node::new(NodeType::VaEnd(Box::new(ap)))
+            }
             _ => t.bad_token("number expected"),
         }
     }
 
+    // Reads the arguments of a function call after the opening parenthesis.
+    fn call_args(&mut self) -> Vec<Node> {
+        let mut args: Vec<Node> = vec![];
+        if self.consume(TokenType::RightParen) {
+            return args;
+        }
+
+        args.push(self.assign());
+        while self.consume(TokenType::Comma) {
+            args.push(self.assign());
+        }
+        self.expect(TokenType::RightParen);
+        args
+    }
+
     fn postfix(&mut self) -> Node {
-        let mut lhs = self.primary();
+        let lhs = self.primary();
+        self.postfix_ops(lhs)
+    }
 
+    fn postfix_ops(&mut self, mut lhs: Node) -> Node {
         loop {
             if self.consume(TokenType::Inc) {
                 lhs = new_expr!(NodeType::PostInc, lhs);
@@ -404,6 +772,11 @@ impl<'a> Parser<'a> {
                 continue;
             }
 
+            if self.consume(TokenType::LeftParen) {
+                lhs = Node::new(NodeType::CallPtr(Box::new(lhs), self.call_args()));
+                continue;
+            }
+
             if self.consume(TokenType::LeftBracket) {
                 lhs = new_expr!(
                     NodeType::Deref,
@@ -430,9 +803,15 @@ impl<'a> Parser<'a> {
             return new_expr!(NodeType::Exclamation, self.unary());
         }
         if self.consume(TokenType::Sizeof) {
+            if self.is_paren_typename() {
+                return Node::new_int(self.paren_type_name().size as i32);
+            }
             return new_expr!(NodeType::Sizeof, self.unary());
         }
         if self.consume(TokenType::Alignof) {
+            if self.is_paren_typename() {
+                return Node::new_int(self.paren_type_name().align as i32);
+            }
             return new_expr!(NodeType::Alignof, self.unary());
         }
         if self.consume(TokenType::Inc) {
@@ -441,6 +820,17 @@ impl<'a> Parser<'a> {
         if self.consume(TokenType::Dec) {
             return Node::new_binop(TokenType::SubEQ, self.unary(), Node::new_num(1));
         }
+        if self.is_paren_typename() {
+            let ty = self.paren_type_name();
+            if self.tokens[self.pos].ty == TokenType::LeftBrace {
+                let mut node = new_expr!(NodeType::CompLit, self.initializer());
+                node.ty = Box::new(ty);
+                return self.postfix_ops(node);
+            }
+            let mut node = new_expr!(NodeType::Cast, self.unary());
+            node.ty = Box::new(ty);
+            return node;
+        }
 
         self.postfix()
     }
@@ -494,11 +884,11 @@ impl<'a> Parser<'a> {
             if self.consume(TokenType::LeftAngleBracket) {
                 lhs = Node::new_binop(TokenType::LeftAngleBracket, lhs, self.shift());
             } else if self.consume(TokenType::RightAngleBracket) {
-                lhs = Node::new_binop(TokenType::LeftAngleBracket, self.shift(), lhs);
+                lhs = Node::new_binop(TokenType::RightAngleBracket, lhs, self.shift());
             } else if self.consume(TokenType::LE) {
                 lhs = Node::new_binop(TokenType::LE, lhs, self.shift())
             } else if self.consume(TokenType::GE) {
-                lhs = Node::new_binop(TokenType::LE, self.shift(), lhs);
+                lhs = Node::new_binop(TokenType::GE, lhs, self.shift());
             } else {
                 return lhs;
             }
@@ -600,11 +990,22 @@ impl<'a> Parser<'a> {
         Node::new_binop(TokenType::Comma, lhs, self.expr())
     }
 
+    fn const_expr(&mut self) -> i32 {
+        let t = &self.tokens[self.pos];
+        let node = self.conditional();
+        if let Some(val) = node.eval() {
+            val
+        } else {
+            t.bad_token("constant expression expected");
+        }
+    }
+
     fn ctype(&mut self) -> Type {
         let t = &self.tokens[self.pos];
         if let Some(mut ty) = self.decl_specifiers() {
             while self.consume(TokenType::Mul) {
                 ty = Type::ptr_to(Box::new(ty));
+                self.qualifiers(&mut ty);
             }
             ty
         } else {
@@ -612,6 +1013,25 @@ impl<'a> Parser<'a> {
         }
     }
 
+    // Reads a type name without a declared identifier, as in a cast
+    // or sizeof (e.g. `char *` or `int [3]`).
+    fn type_name(&mut self) -> Type {
+        let ty = self.ctype();
+        self.read_array(Box::new(ty))
+    }
+
+    fn is_paren_typename(&self) -> bool {
+        self.tokens[self.pos].ty == TokenType::LeftParen
+            && self.is_typename(&self.tokens[self.pos + 1])
+    }
+
+    fn paren_type_name(&mut self) -> Type {
+        self.expect(TokenType::LeftParen);
+        let ty = self.type_name();
+        self.expect(TokenType::RightParen);
+        ty
+    }
+
     fn read_array(&mut self, mut ty: Box<Type>) -> Type {
         let mut v: Vec<usize> = vec![];
         while self.consume(TokenType::LeftBracket) {
@@ -621,7 +1041,7 @@ impl<'a> Parser<'a> {
             }
 
             let len = self.expr();
-            if let NodeType::Num(n) = len.op {
+            if let Some(n) = len.eval() {
                 v.push(n as usize);
                 self.expect(TokenType::RightBracket);
             } else {
@@ -636,32 +1056,65 @@ impl<'a> Parser<'a> {
         *ty
     }
 
-    fn array_init_rval(&mut self, ident: Node) -> Node {
-        let mut init = vec![];
-        let mut i = 0;
-        loop {
-            let val = self.primary();
-            let node = new_expr!(
-                NodeType::Deref,
-                Node::new_binop(TokenType::Plus, ident.clone(), Node::new(NodeType::Num(i)))
-            );
-            init.push(Node::new(NodeType::ExprStmt(Box::new(Node::new_binop(
-                TokenType::Equal,
-                node,
-                val,
-            )))));
+    // Reads an initializer, which is either an expression or a
+    // brace-enclosed list of initializers.
+    fn initializer(&mut self) -> Node {
+        if !self.consume(TokenType::LeftBrace) {
+            return self.assign();
+        }
+
+        let mut items = vec![];
+        while !self.consume(TokenType::RightBrace) {
+            let t = &self.tokens[self.pos];
+            if t.ty == TokenType::Dot || t.ty == TokenType::LeftBracket {
+                items.push(self.designation());
+            } else {
+                items.push(self.initializer());
+            }
             if !self.consume(TokenType::Comma) {
+                self.expect(TokenType::RightBrace);
                 break;
             }
-            i += 1;
         }
-        self.expect(TokenType::RightBrace);
-        Node::new(NodeType::VecStmt(init))
+        Node::new(NodeType::InitList(items))
+    }
+
+    // Reads a designator and the initializer of the object it selects,
+    // such as `.pos.x = 1` or `[2] = {1, 2}`.
+    fn designation(&mut self) -> Node {
+        if self.consume(TokenType::Dot) {
+            let name = self.ident();
+            return Node::new(NodeType::MemberDesig(name, Box::new(self.designated())));
+        }
+
+        self.expect(TokenType::LeftBracket);
+        let index = self.const_expr();
+        if index < 0 {
+            self.tokens[self.pos].bad_token("negative array index in initializer");
+        }
+        self.expect(TokenType::RightBracket);
+        Node::new(NodeType::IndexDesig(
+            index as usize,
+            Box::new(self.designated()),
+        ))
+    }
+
+    fn designated(&mut self) -> Node {
+        let t = &self.tokens[self.pos];
+        if t.ty == TokenType::Dot || t.ty == TokenType::LeftBracket {
+            return self.designation();
+        }
+        self.expect(TokenType::Equal);
+        self.initializer()
     }
 
     fn update_ptr_to(&mut self, src: &mut Box<Type>, dst: Box<Type>) {
         match src.ty {
             Ctype::Ptr(ref mut ptr_to) => self.update_ptr_to(ptr_to, dst),
+            Ctype::Ary(ref mut ary_of, len) => {
+                self.update_ptr_to(ary_of, dst);
+                *src = Box::new(Type::ary_of(ary_of.clone(), len));
+            }
             _ => *src = dst,
         }
     }
@@ -680,46 +1133,105 @@ impl<'a> Parser<'a> {
             t.bad_token("bad direct-declarator");
         }
 
-        // Read the second half of type name (e.g. `[3][5]`).
-        let ty = self.read_array(ty);
+        // Read the second half of type name (e.g. `[3][5]` or `(int, char)`).
+        let ty = if self.consume(TokenType::LeftParen) {
+            let (params, is_variadic) = self.param_types();
+            Type::new(Ctype::Func(ty, params, is_variadic), 0)
+        } else {
+            self.read_array(ty)
+        };
         self.update_ptr_to(&mut node.ty, Box::new(ty));
+        node
+    }
 
-        // Read an initializer.
-        let init: Option<Box<Node>>;
-        if self.consume(TokenType::Equal) {
-            // Assign a value when initializing an array.
-            if let TokenType::Ident(ref name) = t.ty {
-                if self.consume(TokenType::LeftBrace) {
-                    let mut stmts = vec![];
-                    let mut ary_declaration =
-                        Node::new(NodeType::Vardef(name.clone(), None, Scope::Local(0)));
-                    ary_declaration.ty = node.ty;
-                    stmts.push(ary_declaration);
-                    let init_ary = self.array_init_rval(Node::new(NodeType::Ident(name.clone())));
-                    stmts.push(init_ary);
-                    return Node::new(NodeType::VecStmt(stmts));
-                }
-            }
+    // Reads the parameter list of a function declarator, such as the
+    // one of a pointer to function. The names of the parameters are
+    // discarded. Returns their types and whether it is variadic.
+    fn param_types(&mut self) -> (Vec<Type>, bool) {
+        let mut params = vec![];
+        if self.consume(TokenType::RightParen) {
+            return (params, false);
+        }
+        if self.tokens[self.pos].ty == TokenType::Void
+            && self.tokens[self.pos + 1].ty == TokenType::RightParen
+        {
+            self.pos += 2;
+            return (params, false);
+        }
 
-            init = Some(Box::new(self.assign()));
-            match node.op {
-                NodeType::Vardef(_, ref mut init2, _) => *init2 = init,
-                _ => unreachable!(),
+        let mut is_variadic = false;
+        loop {
+            if self.consume(TokenType::Ellipsis) {
+                is_variadic = true;
+                break;
+            }
+            let mut ty = self.ctype();
+            ty = match self.tokens[self.pos].ty {
+                TokenType::Ident(_) | TokenType::LeftParen => *self.declarator(&mut ty).ty,
+                _ => self.read_array(Box::new(ty)),
+            };
+            params.push(Self::param_type(ty));
+            if !self.consume(TokenType::Comma) {
+                break;
             }
         }
-        node
+        self.expect(TokenType::RightParen);
+        (params, is_variadic)
     }
 
     fn declarator(&mut self, ty: &mut Type) -> Node {
         while self.consume(TokenType::Mul) {
             *ty = Type::ptr_to(Box::new(ty.clone()));
+            self.qualifiers(ty);
         }
         self.direct_decl(Box::new(ty.clone()))
     }
 
     fn declaration(&mut self) -> Node {
-        let mut ty = self.decl_specifiers().unwrap();
-        let node = self.declarator(&mut ty);
+        let ty = self.decl_specifiers().unwrap();
+        self.init_declarator(ty)
+    }
+
+    // A declaration in a block, which may also define a typedef or a
+    // static variable.
+    fn block_declaration(&mut self) -> Node {
+        let (ty, storage) = self.specifiers().unwrap();
+        let node = self.init_declarator(ty);
+        let t = match storage {
+            Some(t) if !matches!(node.op, NodeType::Null) => t,
+            _ => return node,
+        };
+        match t.ty {
+            TokenType::Typedef => {
+                if let NodeType::Vardef(name, _, _) = node.op {
+                    self.env.typedefs.insert(name, *node.ty);
+                    Node::new(NodeType::Null)
+                } else {
+                    unreachable!();
+                }
+            }
+            // A static local variable is initialized like a global one.
+            TokenType::Static => Node::new(NodeType::Static(Box::new(node))),
+            _ => t.bad_token("storage class not allowed here"),
+        }
+    }
+
+    // Reads the declarator and the initializer of a declaration.
+    fn init_declarator(&mut self, mut ty: Type) -> Node {
+        if self.consume(TokenType::Semicolon) {
+            return Node::new(NodeType::Null);
+        }
+        let mut node = self.declarator(&mut ty);
+        self.add_var(&node);
+
+        // Read an initializer.
+        if self.consume(TokenType::Equal) {
+            let init = Some(Box::new(self.initializer()));
+            match node.op {
+                NodeType::Vardef(_, ref mut init2, _) => *init2 = init,
+                _ => unreachable!(),
+            }
+        }
         self.expect(TokenType::Semicolon);
         node
     }
@@ -727,12 +1239,28 @@ impl<'a> Parser<'a> {
     fn param_declaration(&mut self) -> Node {
         let mut ty = self.decl_specifiers().unwrap();
         let mut node = self.declarator(&mut ty);
-        if let Ctype::Ary(ary_of, _) = node.ty.ty {
-            node.ty = Box::new(Type::ptr_to(ary_of));
-        }
+        self.add_var(&node);
+        node.ty = Box::new(Self::param_type(*node.ty));
         node
     }
 
+    // Records a declared name in the current scope, where it
+    // shadows enumerators of the same name.
+    fn add_var(&mut self, node: &Node) {
+        if let NodeType::Vardef(ref name, _, _) = node.op {
+            self.env.vars.insert(name.clone(), None);
+        }
+    }
+
+    // A parameter of array or function type is a pointer.
+    fn param_type(ty: Type) -> Type {
+        match ty.ty {
+            Ctype::Ary(ary_of, _) => Type::ptr_to(ary_of),
+            Ctype::Func(_, _, _) => Type::ptr_to(Box::new(ty)),
+            _ => ty,
+        }
+    }
+
     fn expr_stmt(&mut self) -> Node {
         let expr = self.expr();
         let node = new_expr!(NodeType::ExprStmt, expr);
@@ -745,15 +1273,6 @@ impl<'a> Parser<'a> {
         self.pos += 1;
 
         match t.ty {
-            TokenType::Typedef => {
-                let node = self.declaration();
-                if let NodeType::Vardef(name, _, _) = node.op {
-                    self.env.typedefs.insert(name, *node.ty);
-                    return Node::new(NodeType::Null);
-                } else {
-                    unreachable!();
-                }
-            }
             TokenType::If => {
                 let mut els = None;
                 self.expect(TokenType::LeftParen);
@@ -767,6 +1286,7 @@ impl<'a> Parser<'a> {
             }
             TokenType::For => {
                 self.expect(TokenType::LeftParen);
+                self.env = Env::new(Some(Box::new(self.env.clone())));
 
                 let init: Box<Node> = if self.is_typename(&self.tokens[self.pos]) {
                     Box::new(self.declaration())
@@ -793,6 +1313,7 @@ impl<'a> Parser<'a> {
                 }
 
                 let body = Box::new(self.stmt());
+                self.pop_env();
                 Node::new(NodeType::For(init, cond, inc, body))
             }
             TokenType::While => {
@@ -817,6 +1338,53 @@ impl<'a> Parser<'a> {
                 self.expect(TokenType::Semicolon);
                 Node::new(NodeType::Break)
             }
+            TokenType::Continue => {
+                self.expect(TokenType::Semicolon);
+                Node::new(NodeType::Continue)
+            }
+            TokenType::Goto => {
+                self.gotos.push(self.pos);
+                let name = self.ident();
+                self.expect(TokenType::Semicolon);
+                Node::new(NodeType::Goto(name))
+            }
+            TokenType::Switch => {
+                self.expect(TokenType::LeftParen);
+                let cond = Box::new(self.expr());
+                self.expect(TokenType::RightParen);
+
+                self.switches.push((vec![], false));
+                let body = Box::new(self.stmt());
+                let (cases, has_default) = self.switches.pop().unwrap();
+                Node::new(NodeType::Switch(cond, body, cases, has_default))
+            }
+            TokenType::Case => {
+                let val = self.const_expr();
+                self.expect(TokenType::Colon);
+                // Duplicates are found in sema, once the values are
+                // converted to the type of the controlling expression.
+                let idx = match self.switches.last_mut() {
+                    Some((cases, _)) => {
+                        cases.push(val);
+                        cases.len() - 1
+                    }
+                    None => t.bad_token("stray 'case' label"),
+                };
+                Node::new(NodeType::Case(idx, Box::new(self.stmt())))
+            }
+            TokenType::Default => {
+                self.expect(TokenType::Colon);
+                match self.switches.last_mut() {
+                    Some((_, has_default)) => {
+                        if *has_default {
+                            t.bad_token("multiple default labels in one switch");
+                        }
+                        *has_default = true;
+                    }
+                    None => t.bad_token("stray 'default' label"),
+                }
+                Node::new(NodeType::Default(Box::new(self.stmt())))
+            }
             TokenType::Return => {
                 let expr = self.expr();
                 self.expect(TokenType::Semicolon);
@@ -831,18 +1399,21 @@ impl<'a> Parser<'a> {
                 self.expect(TokenType::Semicolon);
                 Node::new(NodeType::Outchar(Box::new(ch), Box::new(pos)))
             }
-            TokenType::LeftBrace => {
-                let mut stmts = vec![];
-                while !self.consume(TokenType::RightBrace) {
-                    stmts.push(self.stmt());
+            TokenType::LeftBrace => self.compound_stmt(),
+            TokenType::Semicolon => Node::new(NodeType::Null),
+            TokenType::Ident(ref name)
+                if self.tokens.get(self.pos).map(|t| &t.ty) == Some(&TokenType::Colon) =>
+            {
+                self.pos += 1;
+                if !self.labels.insert(name.clone()) {
+                    t.bad_token(&format!("duplicate label: {}", name));
                 }
-                Node::new(NodeType::CompStmt(stmts))
+                Node::new(NodeType::Label(name.clone(), Box::new(self.stmt())))
             }
-            TokenType::Semicolon => Node::new(NodeType::Null),
             _ => {
                 self.pos -= 1;
                 if self.is_typename(&self.tokens[self.pos]) {
-                    return self.declaration();
+                    return self.block_declaration();
                 }
                 self.expr_stmt()
             }
@@ -857,17 +1428,56 @@ impl<'a> Parser<'a> {
         while !self.consume(TokenType::RightBrace) {
             stmts.push(self.stmt());
         }
+        self.pop_env();
+        Node::new(NodeType::CompStmt(stmts))
+    }
+
+    fn pop_env(&mut self) {
         let next = self.env.next.clone();
         self.env = *next.unwrap();
-        Node::new(NodeType::CompStmt(stmts))
     }
 
     fn toplevel(&mut self) -> Option<Node> {
-        let is_typedef = self.consume(TokenType::Typedef);
-        let is_extern = self.consume(TokenType::Extern);
+        let t = &self.tokens[self.pos];
+        let (ty, storage) = match self.specifiers() {
+            Some(specs) => specs,
+            None => t.bad_token("typename expected"),
+        };
+        let storage = storage.map(|t| &t.ty);
+        let node = self.external_decl(
+            ty,
+            storage == Some(&TokenType::Typedef),
+            storage == Some(&TokenType::Extern),
+        );
+        // Internal linkage
+        if storage == Some(&TokenType::Static) {
+            return node.map(|node| Node::new(NodeType::Static(Box::new(node))));
+        }
+        node
+    }
+
+    // Reads the rest of a declaration or a function definition at file
+    // scope, after its specifiers.
+    fn external_decl(&mut self, mut ty: Type, is_typedef: bool, is_extern: bool) -> Option<Node> {
+        while self.consume(TokenType::Mul) {
+            ty = Type::ptr_to(Box::new(ty));
+            self.qualifiers(&mut ty);
+        }
+        if self.consume(TokenType::Semicolon) {
+            // A declaration of a struct tag only.
+            return None;
+        }
 
-        let mut ty = self.ctype();
         let t = &self.tokens[self.pos];
+        if t.ty == TokenType::LeftParen {
+            // A declarator in parentheses, such as a pointer to function.
+            let node = self.direct_decl(Box::new(ty));
+            if let NodeType::Vardef(name, _, _) = node.op {
+                return self.global_var(name, *node.ty, is_typedef, is_extern);
+            }
+            unreachable!();
+        }
+
         let name: String;
         if let TokenType::Ident(ref name2) = t.ty {
             name = name2.clone();
@@ -878,20 +1488,31 @@ impl<'a> Parser<'a> {
 
         // Function
         if self.consume(TokenType::LeftParen) {
+            self.env.vars.insert(name.clone(), None);
+            // The parameters are in scope until the end of the body.
+            self.env = Env::new(Some(Box::new(self.env.clone())));
             let mut args = vec![];
+            let mut is_variadic = false;
             if self.consume(TokenType::Void) {
                 self.expect(TokenType::RightParen);
             } else if !self.consume(TokenType::RightParen) {
                 args.push(self.param_declaration());
                 while self.consume(TokenType::Comma) {
+                    if self.consume(TokenType::Ellipsis) {
+                        is_variadic = true;
+                        break;
+                    }
                     args.push(self.param_declaration());
                 }
                 self.expect(TokenType::RightParen);
             }
+            let params = args.iter().map(|arg| *arg.ty.clone()).collect();
+            let func_ty = Type::new(Ctype::Func(Box::new(ty), params, is_variadic), 0);
 
             if self.consume(TokenType::Semicolon) {
+                self.pop_env();
                 let mut node = Node::new(NodeType::Decl(name));
-                node.ty = Box::new(Type::new(Ctype::Func(Box::new(ty)), 0));
+                node.ty = Box::new(func_ty);
                 return Some(node);
             }
 
@@ -901,14 +1522,41 @@ impl<'a> Parser<'a> {
                 t.bad_token("typedef {} has function definition");
             }
             let body = self.compound_stmt();
+            self.pop_env();
+
+            for pos in self.gotos.drain(..) {
+                let t = &self.tokens[pos];
+                if let TokenType::Ident(ref label) = t.ty {
+                    if !self.labels.contains(label) {
+                        t.bad_token(&format!("undefined label: {}", label));
+                    }
+                }
+            }
+            self.labels.clear();
 
             let mut node = Node::new(NodeType::Func(name, args, Box::new(body), 0));
-            node.ty = Box::new(Type::new(Ctype::Func(Box::new(ty)), 0));
+            node.ty = Box::new(func_ty);
             return Some(node);
         }
 
         ty = self.read_array(Box::new(ty));
+        self.global_var(name, ty, is_typedef, is_extern)
+    }
+
+    // Reads the rest of the declaration of a global variable or a typedef.
+    fn global_var(
+        &mut self,
+        name: String,
+        ty: Type,
+        is_typedef: bool,
+        is_extern: bool,
+    ) -> Option<Node> {
+        let mut init = None;
+        if !is_typedef && self.consume(TokenType::Equal) {
+            init = Some(Box::new(self.initializer()));
+        }
         self.expect(TokenType::Semicolon);
+        self.env.vars.insert(name.clone(), None);
 
         if is_typedef {
             self.env.typedefs.insert(name.clone(), ty.clone());
@@ -918,7 +1566,7 @@ impl<'a> Parser<'a> {
         // Global variable
         let mut node = Node::new(NodeType::Vardef(
             name,
-            None,
+            init,
             if is_extern {
                 Scope::Global(String::new(), 0, true)
             } else {
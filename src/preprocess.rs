@@ -1,14 +1,101 @@
 // C preprocessor
 
-use crate::token::{tokenize, Token};
+use crate::token::{lex, tokenize, Token};
 use crate::TokenType;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::mem;
+use std::path::{Component, Path, PathBuf};
 use std::rc::Rc;
 
-pub fn preprocess(tokens: Vec<Token>, ctx: &mut Preprocessor) -> Vec<Token> {
-    ctx.preprocess_impl(tokens)
+// The bundled headers, embedded in the binary and searched by #include
+// after the -I directories.
+const STD_HEADERS: &[(&str, &str)] = &[
+    ("ncurses.h", include_str!("../std/ncurses.h")),
+    ("stdarg.h", include_str!("../std/stdarg.h")),
+    ("stdbool.h", include_str!("../std/stdbool.h")),
+    ("stdio.h", include_str!("../std/stdio.h")),
+];
+
+pub fn preprocess(tokens: Vec<Token>, path: &str, ctx: &mut Preprocessor) -> Vec<Token> {
+    let guard = include_guard(&tokens);
+    let prev = mem::replace(&mut ctx.path, path.to_string());
+    let output = ctx.preprocess_impl(tokens);
+    ctx.path = prev;
+
+    if let Some(name) = guard {
+        ctx.guards.insert(path.to_string(), name);
+    }
+    output
+}
+
+// Returns the macro guarding a whole file, written as
+//
+//   #ifndef NAME
+//   #define NAME
+//   ...
+//   #endif
+//
+// so that the file can be skipped once NAME is defined.
+fn include_guard(tokens: &[Token]) -> Option<String> {
+    let mut lines = tokens
+        .split(|t| t.ty == TokenType::NewLine)
+        .filter(|line| !line.is_empty());
+
+    let name = match lines.next()? {
+        [hash, dir, name] if hash.ty == TokenType::HashMark && dir.is_ident("ifndef") => {
+            match name.ty {
+                TokenType::Ident(ref name) => name.clone(),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    match lines.next()? {
+        [hash, dir, t, ..]
+            if hash.ty == TokenType::HashMark && dir.is_ident("define") && t.is_ident(&name) => {}
+        _ => return None,
+    }
+
+    let mut depth = 0;
+    let mut closed = false;
+    for line in lines {
+        if closed {
+            return None;
+        }
+        if line.len() < 2 || line[0].ty != TokenType::HashMark {
+            continue;
+        }
+        match &*line[1].tokstr() {
+            "if" | "ifdef" | "ifndef" => depth += 1,
+            "elif" | "else" if depth == 0 => return None,
+            "endif" if depth == 0 => closed = true,
+            "endif" => depth -= 1,
+            _ => (),
+        }
+    }
+
+    if closed {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+// Removes the `.` and `..` components of a path, so that a file included
+// under different names is still recognized.
+fn normalize(path: &Path) -> String {
+    let mut v = vec![];
+    for c in path.components() {
+        match c {
+            Component::CurDir => (),
+            Component::ParentDir if matches!(v.last(), Some(Component::Normal(_))) => {
+                v.pop();
+            }
+            _ => v.push(c),
+        }
+    }
+    v.iter().collect::<PathBuf>().to_string_lossy().into_owned()
 }
 
 #[derive(Clone)]
@@ -16,6 +103,7 @@ pub struct Env {
     input: Vec<Token>,
     output: Vec<Token>,
     pos: usize,
+    conds: Vec<Cond>,
     next: Option<Box<Env>>,
 }
 
@@ -25,11 +113,20 @@ impl Default for Env {
             input: vec![],
             output: vec![],
             pos: 0,
+            conds: vec![],
             next: None,
         }
     }
 }
 
+// An #if, #ifdef or #ifndef whose #endif has not been read yet.
+#[derive(Clone)]
+struct Cond {
+    start: Token,   // the directive, for error reporting
+    included: bool, // whether one of its groups was included
+    in_else: bool,  // whether its #else was read
+}
+
 impl Env {
     pub fn new(input: Vec<Token>, next: Option<Box<Env>>) -> Self {
         Env {
@@ -50,11 +147,16 @@ enum MacroType {
 struct Macro {
     ty: MacroType,
     pub tokens: Vec<Token>,
+    variadic: bool, // whether the last parameter is `...`
 }
 
 impl Macro {
     fn new(ty: MacroType) -> Self {
-        Macro { ty, tokens: vec![] }
+        Macro {
+            ty,
+            tokens: vec![],
+            variadic: false,
+        }
     }
 
     fn replace_params(mut self) -> Self {
@@ -131,6 +233,12 @@ pub struct Preprocessor {
     macros: HashMap<String, Macro>,
     pub env: Box<Env>,
     file_provider: Box<dyn Fn(&str) -> Option<String>>,
+
+    // For #include
+    include_dirs: Vec<String>,       // Set by -I
+    path: String,                    // File being preprocessed
+    once: HashSet<String>,           // Files with #pragma once
+    guards: HashMap<String, String>, // Files with include guards
 }
 
 impl Preprocessor {
@@ -139,9 +247,30 @@ impl Preprocessor {
             macros: HashMap::new(),
             env: Box::new(Env::new(vec![], None)),
             file_provider,
+            include_dirs: vec![],
+            path: String::new(),
+            once: HashSet::new(),
+            guards: HashMap::new(),
         }
     }
 
+    pub fn add_include_dir(&mut self, dir: String) {
+        self.include_dirs.push(dir);
+    }
+
+    // Defines a macro given as NAME or NAME=value, as with -D. A macro
+    // without a value is defined as 1.
+    pub fn define_macro(&mut self, def: &str) {
+        let (name, value) = def.split_once('=').unwrap_or((def, "1"));
+        let src = format!("#define {} {}\n", name, value);
+        tokenize(src, "<command line>".to_string(), self);
+    }
+
+    pub fn undef_macro(&mut self, name: &str) {
+        let src = format!("#undef {}\n", name);
+        tokenize(src, "<command line>".to_string(), self);
+    }
+
     fn next(&mut self) -> Option<Token> {
         if self.eof() {
             return None;
@@ -224,20 +353,37 @@ impl Preprocessor {
         start.bad_token(msg);
     }
 
-    fn read_args(&mut self) -> Vec<Vec<Token>> {
+    // Arguments from the `nparams`th on of a variadic macro are read as a
+    // single one, commas included.
+    fn read_args(&mut self, nparams: usize, variadic: bool) -> Vec<Vec<Token>> {
         let mut v = vec![];
         if self.consume(TokenType::RightParen) {
             return v;
         }
         v.push(self.read_one_arg());
         while !self.consume(TokenType::RightParen) {
-            self.get(TokenType::Comma, "comma expected");
-            v.push(self.read_one_arg());
+            let comma = self.get(TokenType::Comma, "comma expected");
+            let mut arg = self.read_one_arg();
+            if variadic && v.len() == nparams {
+                let last = v.last_mut().unwrap();
+                last.push(comma);
+                last.append(&mut arg);
+            } else {
+                v.push(arg);
+            }
         }
         v
     }
 
-    fn stringize(tokens: &[Token], filename: Rc<String>, buf: Rc<Vec<char>>) -> Token {
+    // Makes a token whose buffer holds its own spelling.
+    fn new_token(ty: TokenType, text: String, filename: Rc<String>) -> Token {
+        let buf: Vec<char> = text.chars().collect();
+        let mut t = Token::new(ty, 0, filename, Rc::new(buf));
+        t.end = t.buf.len();
+        t
+    }
+
+    fn stringize(tokens: &[Token], filename: Rc<String>) -> Token {
         let mut sb = String::new();
         for (i, t) in tokens.iter().enumerate() {
             if i != 0 {
@@ -247,77 +393,184 @@ impl Preprocessor {
         }
 
         let len = sb.len();
-        Token::new(TokenType::Str(sb, len), 0, filename, buf)
+        let text = format!("\"{}\"", sb.replace('\\', "\\\\").replace('"', "\\\""));
+        Self::new_token(TokenType::Str(sb, len), text, filename)
     }
 
-    fn add_special_macro(&mut self, t: &Token) -> bool {
+    // Pastes two tokens into a new one, lexing their spellings again.
+    fn paste(lhs: &Token, rhs: &Token) -> Token {
+        let text = format!("{}{}", lhs.tokstr(), rhs.tokstr());
+        let mut v = lex(text.clone(), lhs.filename.clone());
+        match v.pop() {
+            Some(t) if v.is_empty() && !matches!(t.ty, TokenType::Invalid(_)) => t,
+            _ => lhs.bad_token(&format!("pasting does not give a valid token: {}", text)),
+        }
+    }
+
+    // `at` is the token the special macro is expanded at.
+    fn special_macro(t: &Token, at: &Token) -> Option<Token> {
         if t.is_ident("__LINE__") {
-            self.env.output.push(Token::new(
-                TokenType::Num(t.get_line_number() as i32),
-                0,
+            let line = at.get_line_number();
+            Some(Self::new_token(
+                TokenType::Num(line as i32),
+                line.to_string(),
                 t.filename.clone(),
-                t.buf.clone(),
-            ));
-            true
+            ))
         } else {
-            false
+            None
         }
     }
 
-    fn apply_objlike(&mut self, tokens: Vec<Token>) {
-        for t in tokens {
-            if self.add_special_macro(&t) {
-                continue;
-            } else {
-                self.env.output.push(t);
+    // Returns the index of the parenthesis closing a `__VA_OPT__(`
+    // starting at `pos`.
+    fn va_opt_end(tokens: &[Token], pos: usize, start: &Token) -> usize {
+        if tokens.get(pos).map(|t| &t.ty) != Some(&TokenType::LeftParen) {
+            start.bad_token("'(' expected after __VA_OPT__");
+        }
+
+        let mut level = 0;
+        for (i, t) in tokens.iter().enumerate().skip(pos) {
+            if t.ty == TokenType::LeftParen {
+                level += 1;
+            } else if t.ty == TokenType::RightParen {
+                level -= 1;
+                if level == 0 {
+                    return i;
+                }
             }
         }
+        start.bad_token("unterminated __VA_OPT__");
     }
 
-    fn apply_funclike(&mut self, tokens: Vec<Token>, params: &[String], start: &Token) {
-        self.get(TokenType::LeftParen, "comma expected");
-        let args = self.read_args();
-        if params.len() != args.len() {
-            start.bad_token("number of parameter does not match");
+    // Expands one operand of a macro body, which is a token or a
+    // `__VA_OPT__(...)` group, and moves `i` past it. Special macros next
+    // to `##` are not expanded.
+    fn operand(
+        &self,
+        m: &Macro,
+        tokens: &[Token],
+        i: &mut usize,
+        args: &[Vec<Token>],
+        start: &Token,
+        pasted: bool,
+    ) -> Vec<Token> {
+        let t = &tokens[*i];
+        *i += 1;
+
+        match t.ty {
+            TokenType::Param(val) if t.stringize => {
+                vec![Self::stringize(&args[val], t.filename.clone())]
+            }
+            TokenType::Param(val) => args[val].clone(),
+            _ if m.variadic && t.is_ident("__VA_OPT__") => {
+                let end = Self::va_opt_end(tokens, *i, t);
+                let body = &tokens[*i + 1..end];
+                *i = end + 1;
+                match args.last() {
+                    Some(arg) if !arg.is_empty() => self.subst(m, body, args, start),
+                    _ => vec![],
+                }
+            }
+            _ => match Self::special_macro(t, start) {
+                Some(t) if !pasted => vec![t],
+                _ => vec![t.clone()],
+            },
         }
+    }
+
+    // Replaces the parameters of a macro body with `args` and pastes the
+    // tokens around each `##`.
+    fn subst(&self, m: &Macro, tokens: &[Token], args: &[Vec<Token>], start: &Token) -> Vec<Token> {
+        let mut v: Vec<Token> = vec![];
+        let mut last = 0; // where the last operand starts in v
+        let mut i = 0;
 
-        for t in tokens {
-            if self.add_special_macro(&t) {
+        while i < tokens.len() {
+            if tokens[i].ty != TokenType::HashHash {
+                let pasted = tokens
+                    .get(i + 1)
+                    .is_some_and(|t| t.ty == TokenType::HashHash);
+                last = v.len();
+                v.append(&mut self.operand(m, tokens, &mut i, args, start, pasted));
                 continue;
             }
 
-            match t.ty {
-                TokenType::Param(val) => {
-                    if t.stringize {
-                        self.env
-                            .output
-                            .push(Self::stringize(&args[val], t.filename, t.buf));
-                    } else {
-                        self.env.output.append(&mut args[val].clone());
-                    }
+            i += 1;
+            let rhs = self.operand(m, tokens, &mut i, args, start, true);
+            // An empty operand leaves the other one as it is.
+            if v.len() == last {
+                v.extend(rhs);
+                continue;
+            }
+            let lhs = v.pop().unwrap();
+            last = v.len();
+            match rhs.split_first() {
+                Some((t, rest)) => {
+                    v.push(Self::paste(&lhs, t));
+                    v.extend_from_slice(rest);
                 }
-                _ => self.env.output.push(t),
+                None => v.push(lhs),
             }
         }
+        v
+    }
+
+    fn apply_objlike(&mut self, m: &Macro, start: &Token) {
+        let mut v = self.subst(m, &m.tokens, &[], start);
+        self.env.output.append(&mut v);
+    }
+
+    fn apply_funclike(&mut self, m: &Macro, params: &[String], start: &Token) {
+        self.get(TokenType::LeftParen, "comma expected");
+        let mut args = self.read_args(params.len(), m.variadic);
+        // A single empty argument, or an empty variable argument.
+        if args.len() + 1 == params.len() && (m.variadic || args.is_empty()) {
+            args.push(vec![]);
+        }
+        if params.len() != args.len() {
+            start.bad_token("number of parameter does not match");
+        }
+
+        let mut v = self.subst(m, &m.tokens, &args, start);
+        self.env.output.append(&mut v);
     }
 
     fn apply(&mut self, m: Macro, start: &Token) {
         match m.ty {
-            MacroType::Objlike => self.apply_objlike(m.tokens),
-            MacroType::Funclike(ref params) => self.apply_funclike(m.tokens, params, start),
+            MacroType::Objlike => self.apply_objlike(&m, start),
+            MacroType::Funclike(ref params) => self.apply_funclike(&m, params, start),
+        }
+    }
+
+    // `##` cannot be at either end of a macro body.
+    fn check_paste(tokens: &[Token]) {
+        for t in [tokens.first(), tokens.last()].iter().flatten() {
+            if t.ty == TokenType::HashHash {
+                t.bad_token("'##' cannot be at either end of a macro");
+            }
         }
     }
 
     fn funclike_macro(&mut self, name: String) {
         let mut params = vec![];
-        params.push(self.ident("parameter name expected"));
+        let mut variadic = false;
         while !self.consume(TokenType::RightParen) {
-            self.get(TokenType::Comma, "comma expected");
+            if !params.is_empty() {
+                self.get(TokenType::Comma, "comma expected");
+            }
+            if self.consume(TokenType::Ellipsis) {
+                params.push("__VA_ARGS__".to_string());
+                variadic = true;
+                self.get(TokenType::RightParen, "')' expected");
+                break;
+            }
             params.push(self.ident("parameter name expected"));
         }
 
         let mut m = Macro::new(MacroType::Funclike(params));
+        m.variadic = variadic;
         m.tokens = self.read_until_eol();
+        Self::check_paste(&m.tokens);
         m = m.replace_params();
         self.macros.insert(name, m);
     }
@@ -325,6 +578,7 @@ impl Preprocessor {
     fn objlike_macro(&mut self, name: String) {
         let mut m = Macro::new(MacroType::Objlike);
         m.tokens = self.read_until_eol();
+        Self::check_paste(&m.tokens);
         self.macros.insert(name, m);
     }
 
@@ -336,17 +590,290 @@ impl Preprocessor {
         self.objlike_macro(name);
     }
 
-    fn include(&mut self) {
-        let path = self.ident("string expected");
-        let t = self.next().expect("newline expected");
-        if t.ty != TokenType::NewLine {
+    fn undef(&mut self) {
+        let name = self.ident("macro name expected");
+        self.expect_newline();
+        self.macros.remove(&name);
+    }
+
+    // Reads the rest of the line as it is written in the source.
+    fn read_message(&mut self) -> String {
+        let tokens = self.read_until_eol();
+        let t = match tokens.first() {
+            Some(t) => t,
+            None => return String::new(),
+        };
+        let end = t.buf[t.start..]
+            .iter()
+            .position(|c| *c == '\n')
+            .map_or(t.buf.len(), |n| t.start + n);
+        let msg: String = t.buf[t.start..end].iter().collect();
+        msg.trim_end().to_string()
+    }
+
+    // Changes the line number of the next line, and optionally the file
+    // name, of the tokens left in the current file.
+    fn line(&mut self, start: &Token) {
+        let mut tokens = vec![];
+        let mut newline = None;
+        while let Some(t) = self.next() {
+            if t.ty == TokenType::NewLine {
+                newline = Some(t);
+                break;
+            }
+            tokens.push(t);
+        }
+        let tokens = self.preprocess_impl(tokens);
+
+        let line = match tokens.first().map(|t| &t.ty) {
+            Some(TokenType::Num(n)) if *n > 0 => *n as isize,
+            _ => start.bad_token("line number expected"),
+        };
+        let filename = match tokens.get(1) {
+            Some(Token {
+                ty: TokenType::Str(s, _),
+                ..
+            }) => Some(Rc::new(s.clone())),
+            Some(t) => t.bad_token("string expected"),
+            None => None,
+        };
+        if let Some(t) = tokens.get(2) {
             t.bad_token("newline expected");
         }
 
-        let mut v = tokenize((self.file_provider)(path.as_str()).unwrap(), path, self);
+        let newline = match newline {
+            Some(t) => t,
+            None => return,
+        };
+        let next_line = newline.get_line_number() as isize - newline.line_delta + 1;
+        let pos = self.env.pos;
+        for t in self.env.input[pos..].iter_mut() {
+            t.line_delta = line - next_line;
+            if let Some(ref filename) = filename {
+                t.filename = filename.clone();
+            }
+        }
+    }
+
+    // Looks for an included file, first next to the current file and in the
+    // working directory if the name is quoted, then in the -I directories
+    // and among the bundled headers.
+    fn find_include(&self, name: &str, quoted: bool) -> Option<(String, String)> {
+        let mut dirs = vec![];
+        if quoted {
+            dirs.push(Path::new(&self.path).parent().unwrap_or(Path::new("")));
+            dirs.push(Path::new(""));
+        }
+        dirs.extend(self.include_dirs.iter().map(Path::new));
+
+        for dir in dirs {
+            let path = normalize(&dir.join(name));
+            if let Some(data) = (self.file_provider)(&path) {
+                return Some((path, data));
+            }
+        }
+
+        STD_HEADERS
+            .iter()
+            .find(|(header, _)| *header == name)
+            .map(|(header, data)| (format!("<{}>", header), data.to_string()))
+    }
+
+    fn include(&mut self, start: &Token) {
+        let tokens = self.read_until_eol();
+        let (name, quoted) = match tokens.first().map(|t| &t.ty) {
+            Some(TokenType::Str(s, _)) if tokens.len() == 1 => (s.clone(), true),
+            Some(TokenType::LeftAngleBracket) => match tokens.last() {
+                Some(t) if tokens.len() > 1 && t.ty == TokenType::RightAngleBracket => {
+                    (t.buf[tokens[0].end..t.start].iter().collect(), false)
+                }
+                _ => start.bad_token("> expected"),
+            },
+            _ => start.bad_token("string expected"),
+        };
+
+        let (path, data) = match self.find_include(&name, quoted) {
+            Some(file) => file,
+            None => start.bad_token(&format!("{}: file not found", name)),
+        };
+        if self.once.contains(&path) {
+            return;
+        }
+        if let Some(guard) = self.guards.get(&path) {
+            if self.macros.contains_key(guard) {
+                return;
+            }
+        }
+
+        let mut v = tokenize(data, path, self);
         self.env.output.append(&mut v);
     }
 
+    fn pragma(&mut self) {
+        let tokens = self.read_until_eol();
+        // Other pragmas are ignored.
+        if tokens.len() == 1 && tokens[0].is_ident("once") {
+            self.once.insert(self.path.clone());
+        }
+    }
+
+    fn directive_name(&mut self) -> String {
+        let t = self.next().expect("identifier expected");
+        match t.ty {
+            TokenType::Ident(s) => s,
+            // `if` and `else` are keywords.
+            TokenType::If | TokenType::Else => t.tokstr(),
+            _ => t.bad_token("identifier expected"),
+        }
+    }
+
+    fn expect_newline(&mut self) {
+        if let Some(t) = self.next() {
+            if t.ty != TokenType::NewLine {
+                t.bad_token("newline expected");
+            }
+        }
+    }
+
+    // Reads the constant expression of an #if or #elif. `defined` is
+    // replaced first, then macros are expanded, and the identifiers left
+    // are replaced with 0.
+    fn read_const_expr(&mut self, start: &Token) -> bool {
+        let mut tokens = vec![];
+        let mut line = self.read_until_eol().into_iter();
+        while let Some(t) = line.next() {
+            if !t.is_ident("defined") {
+                tokens.push(t);
+                continue;
+            }
+            let mut name = line
+                .next()
+                .unwrap_or_else(|| t.bad_token("macro name expected"));
+            let paren = name.ty == TokenType::LeftParen;
+            if paren {
+                name = line
+                    .next()
+                    .unwrap_or_else(|| t.bad_token("macro name expected"));
+            }
+            let val = match name.ty {
+                TokenType::Ident(ref s) => self.macros.contains_key(s),
+                _ => name.bad_token("macro name expected"),
+            };
+            if paren {
+                match line.next() {
+                    Some(ref t) if t.ty == TokenType::RightParen => (),
+                    _ => name.bad_token("')' expected"),
+                }
+            }
+            tokens.push(Token::new(
+                TokenType::Num(val as i32),
+                t.start,
+                t.filename,
+                t.buf,
+            ));
+        }
+
+        let tokens = self
+            .preprocess_impl(tokens)
+            .into_iter()
+            .map(|t| match t.ty {
+                TokenType::Ident(_) => Token::new(TokenType::Num(0), t.start, t.filename, t.buf),
+                _ => t,
+            })
+            .collect::<Vec<_>>();
+        if tokens.is_empty() {
+            start.bad_token("no expression");
+        }
+
+        let mut expr = ConstExpr {
+            tokens: &tokens,
+            pos: 0,
+        };
+        let val = expr.ternary();
+        if let Some(t) = tokens.get(expr.pos) {
+            t.bad_token("extra token in expression");
+        }
+        val != 0
+    }
+
+    // Skips a group up to the #elif, #else or #endif that ends it, which
+    // is left to be read. Conditionals nested in the group are skipped
+    // whole. Tokens are not looked at otherwise, so the group may hold
+    // text that is not valid C.
+    fn skip_cond(&mut self, start: &Token) {
+        let mut depth = 0;
+        let mut bol = true;
+        while let Some(t) = self.peek() {
+            if bol && t.ty == TokenType::HashMark {
+                let name = self.env.input.get(self.env.pos + 1).map(|t| t.tokstr());
+                match name.as_deref() {
+                    Some("if") | Some("ifdef") | Some("ifndef") => depth += 1,
+                    Some("elif") | Some("else") if depth == 0 => return,
+                    Some("endif") if depth == 0 => return,
+                    Some("endif") => depth -= 1,
+                    _ => (),
+                }
+            }
+            bol = t.ty == TokenType::NewLine;
+            self.env.pos += 1;
+        }
+        start.bad_token("unterminated conditional directive");
+    }
+
+    fn push_cond(&mut self, start: Token, included: bool) {
+        self.env.conds.push(Cond {
+            start: start.clone(),
+            included,
+            in_else: false,
+        });
+        if !included {
+            self.skip_cond(&start);
+        }
+    }
+
+    fn ifdef(&mut self, start: Token, expected: bool) {
+        let name = self.ident("macro name expected");
+        self.expect_newline();
+        let defined = self.macros.contains_key(&name);
+        self.push_cond(start, defined == expected);
+    }
+
+    fn elif(&mut self, start: Token) {
+        let cond = match self.env.conds.last() {
+            Some(cond) if !cond.in_else => cond.clone(),
+            _ => start.bad_token("stray #elif"),
+        };
+        if cond.included {
+            self.read_until_eol();
+            return self.skip_cond(&cond.start);
+        }
+        if self.read_const_expr(&start) {
+            self.env.conds.last_mut().unwrap().included = true;
+        } else {
+            self.skip_cond(&cond.start);
+        }
+    }
+
+    fn else_(&mut self, start: Token) {
+        self.expect_newline();
+        let cond = match self.env.conds.last_mut() {
+            Some(cond) if !cond.in_else => cond,
+            _ => start.bad_token("stray #else"),
+        };
+        cond.in_else = true;
+        if cond.included {
+            let start = cond.start.clone();
+            self.skip_cond(&start);
+        }
+    }
+
+    fn endif(&mut self, start: Token) {
+        self.expect_newline();
+        if self.env.conds.pop().is_none() {
+            start.bad_token("stray #endif");
+        }
+    }
+
     fn preprocess_impl(&mut self, tokens: Vec<Token>) -> Vec<Token> {
         self.env = Box::new(Env::new(tokens, Some(self.env.clone())));
 
@@ -359,6 +886,10 @@ impl Preprocessor {
                 macro_name = None;
             }
             if let Some(name) = macro_name {
+                if let Some(t) = Self::special_macro(&t, &t) {
+                    self.env.output.push(t);
+                    continue;
+                }
                 if let Some(m) = self.macros.get(&name).cloned() {
                     self.apply(m, &t);
                 } else {
@@ -372,17 +903,167 @@ impl Preprocessor {
                 continue;
             }
 
-            let ident = self.ident("identifier expected");
+            let ident = self.directive_name();
             match &*ident {
                 "define" => self.define(),
-                "include" => self.include(),
+                "include" => self.include(&t),
+                "undef" => self.undef(),
+                "error" => {
+                    let msg = self.read_message();
+                    t.bad_token(&format!("#error {}", msg));
+                }
+                "warning" => {
+                    let msg = self.read_message();
+                    t.warn(&format!("#warning {}", msg));
+                }
+                "line" => self.line(&t),
+                "pragma" => self.pragma(),
+                "if" => {
+                    let val = self.read_const_expr(&t);
+                    self.push_cond(t, val);
+                }
+                "ifdef" => self.ifdef(t, true),
+                "ifndef" => self.ifdef(t, false),
+                "elif" => self.elif(t),
+                "else" => self.else_(t),
+                "endif" => self.endif(t),
                 _ => t.bad_token("unknown directive"),
             }
         }
 
+        if let Some(cond) = self.env.conds.last() {
+            cond.start.bad_token("unterminated conditional directive");
+        }
+
         let mut output = vec![];
         mem::swap(&mut self.env.output, &mut output);
         self.env = self.env.next.take().unwrap();
         output
     }
 }
+
+// Evaluates the constant expression of an #if, whose macros are
+// already expanded.
+struct ConstExpr<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl<'a> ConstExpr<'a> {
+    fn next(&mut self) -> &'a Token {
+        let t = self
+            .tokens
+            .get(self.pos)
+            .unwrap_or_else(|| self.tokens[self.pos - 1].bad_token("premature end of expression"));
+        self.pos += 1;
+        t
+    }
+
+    fn consume(&mut self, ty: TokenType) -> bool {
+        match self.tokens.get(self.pos) {
+            Some(t) if t.ty == ty => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn primary(&mut self) -> i64 {
+        let t = self.next();
+        match t.ty {
+            TokenType::Num(val) | TokenType::UNum(val) => val as i64,
+            TokenType::LongNum(val, false) => val as i64,
+            TokenType::LongNum(val, true) => val as u32 as i64,
+            TokenType::LeftParen => {
+                let val = self.ternary();
+                if !self.consume(TokenType::RightParen) {
+                    t.bad_token("unclosed parenthesis");
+                }
+                val
+            }
+            _ => t.bad_token("invalid token in expression"),
+        }
+    }
+
+    fn unary(&mut self) -> i64 {
+        if self.consume(TokenType::Minus) {
+            return self.unary().wrapping_neg();
+        }
+        if self.consume(TokenType::Plus) {
+            return self.unary();
+        }
+        if self.consume(TokenType::Exclamation) {
+            return (self.unary() == 0) as i64;
+        }
+        self.primary()
+    }
+
+    // Binary operators, from the tightest binding to the loosest.
+    fn binary(&mut self, prec: usize) -> i64 {
+        use self::TokenType::*;
+        const OPS: [&[TokenType]; 10] = [
+            &[Mul, Div, Mod],
+            &[Plus, Minus],
+            &[SHL, SHR],
+            &[LeftAngleBracket, LE, RightAngleBracket, GE],
+            &[EQ, NE],
+            &[And],
+            &[Hat],
+            &[VerticalBar],
+            &[Logand],
+            &[Logor],
+        ];
+
+        if prec == 0 {
+            return self.unary();
+        }
+        let mut lhs = self.binary(prec - 1);
+        loop {
+            let t = match self.tokens.get(self.pos) {
+                Some(t) if OPS[prec - 1].contains(&t.ty) => t,
+                _ => return lhs,
+            };
+            self.pos += 1;
+            let rhs = self.binary(prec - 1);
+            lhs = match t.ty {
+                Mul => lhs.wrapping_mul(rhs),
+                Div | Mod if rhs == 0 => t.bad_token("division by zero"),
+                Div => lhs.wrapping_div(rhs),
+                Mod => lhs.wrapping_rem(rhs),
+                Plus => lhs.wrapping_add(rhs),
+                Minus => lhs.wrapping_sub(rhs),
+                SHL => lhs.wrapping_shl(rhs as u32),
+                SHR => lhs.wrapping_shr(rhs as u32),
+                LeftAngleBracket => (lhs < rhs) as i64,
+                LE => (lhs <= rhs) as i64,
+                RightAngleBracket => (lhs > rhs) as i64,
+                GE => (lhs >= rhs) as i64,
+                EQ => (lhs == rhs) as i64,
+                NE => (lhs != rhs) as i64,
+                And => lhs & rhs,
+                Hat => lhs ^ rhs,
+                VerticalBar => lhs | rhs,
+                Logand => (lhs != 0 && rhs != 0) as i64,
+                _ => (lhs != 0 || rhs != 0) as i64,
+            };
+        }
+    }
+
+    fn ternary(&mut self) -> i64 {
+        let cond = self.binary(10);
+        if !self.consume(TokenType::Question) {
+            return cond;
+        }
+        let then = self.ternary();
+        if !self.consume(TokenType::Colon) {
+            self.tokens[self.pos - 1].bad_token("':' expected");
+        }
+        let els = self.ternary();
+        if cond != 0 {
+            then
+        } else {
+            els
+        }
+    }
+}
@@ -20,6 +20,7 @@ use std::sync::Mutex;
 lazy_static! {
     static ref USED: Mutex<[bool; REGS_N]> = Mutex::new([false; REGS_N]);
     static ref REG_MAP: Mutex<[Option<usize>; 8192]> = Mutex::new([None; 8192]);
+    static ref SAVED: Mutex<Vec<Vec<usize>>> = Mutex::new(vec![]);
 }
 
 fn used_get(i: usize) -> bool {
@@ -56,7 +57,7 @@ fn alloc(ir_reg: usize) -> usize {
         used_set(i, true);
         return i;
     }
-    panic!("register exhauseted: {}", ir_reg);
+    panic!("expression too complex: out of registers");
 }
 
 fn visit(irv: &mut Vec<IR>) {
@@ -67,24 +68,64 @@ fn visit(irv: &mut Vec<IR>) {
         let info = &IRInfo::from(&ir.op);
 
         match info.ty {
-            Reg | RegImm | RegLabel | LabelAddr => ir.lhs = Some(alloc(ir.lhs.unwrap())),
+            Reg | RegImm | RegLabel | LabelAddr | JmpTable => ir.lhs = Some(alloc(ir.lhs.unwrap())),
             Mem | RegReg => {
                 ir.lhs = Some(alloc(ir.lhs.unwrap()));
                 ir.rhs = Some(alloc(ir.rhs.unwrap()));
             }
             Call => {
-                ir.lhs = Some(alloc(ir.lhs.unwrap()));
+                ir.op = match ir.op {
+                    IROp::Call(name, args, nstack) => {
+                        IROp::Call(name, args.into_iter().map(alloc).collect(), nstack)
+                    }
+                    IROp::CallPtr(args, nstack) => {
+                        IROp::CallPtr(args.into_iter().map(alloc).collect(), nstack)
+                    }
+                    _ => unreachable!(),
+                };
+
                 match ir.op {
-                    IROp::Call(name, nargs, args) => {
-                        let mut args_new: [usize; 6] = [0; 6];
-                        for i in 0..nargs {
-                            args_new[i] = alloc(args[i]);
+                    IROp::Call(_, ref args, _) | IROp::CallPtr(ref args, _) => {
+                        for r in args {
+                            used_set(*r, false);
                         }
-                        ir.op = IROp::Call(name, nargs, args_new);
                     }
                     _ => unreachable!(),
                 }
+
+                // The result must not be overwritten when the saved
+                // registers are restored.
+                let saved = SAVED.lock().unwrap().last().unwrap().clone();
+                for r in &saved {
+                    used_set(*r, true);
+                }
+                ir.lhs = Some(alloc(ir.lhs.unwrap()));
+                for r in &saved {
+                    used_set(*r, false);
+                }
             }
+            // The registers in use before a call are pushed on the stack,
+            // so they can be used to evaluate the arguments. Everything
+            // computed between a Save and its call is dead after the call.
+            RegList => match ir.op {
+                IROp::Save(_) => {
+                    let regs: Vec<usize> = (0..REGS_N).filter(|r| used_get(*r)).collect();
+                    for r in &regs {
+                        used_set(*r, false);
+                    }
+                    SAVED.lock().unwrap().push(regs.clone());
+                    ir.op = IROp::Save(regs);
+                }
+                IROp::Restore(_) => {
+                    let regs = SAVED.lock().unwrap().pop().unwrap();
+                    for r in &regs {
+                        assert!(!used_get(*r));
+                        used_set(*r, true);
+                    }
+                    ir.op = IROp::Restore(regs);
+                }
+                _ => unreachable!(),
+            },
             _ => (),
         }
 
@@ -1,9 +1,9 @@
 use crate::matches;
 use crate::parse::{Node, NodeType};
 use crate::util::roundup;
-use crate::{Ctype, Scope, TokenType, Type, Var};
+use crate::{Ctype, Data, Scope, TokenType, Type, Var};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::mem;
 use std::sync::Mutex;
 
@@ -28,6 +28,15 @@ use std::sync::Mutex;
 // >
 // > - Reject bad assignments, such as `1=2+3`.
 
+// Name of the hidden parameter of a function returning a struct.
+// It points to the object the caller has reserved for the result.
+const RET_PTR: &str = ".ret";
+
+// A local object initialized by a brace-enclosed list is copied from a
+// read-only template if it has at least this many words, which takes
+// less code than storing each of them.
+const INIT_COPY_MIN_WORDS: usize = 4;
+
 fn swap(p: &mut Node, q: &mut Node) {
     mem::swap(p, q);
 }
@@ -36,7 +45,13 @@ lazy_static! {
     static ref GLOBALS: Mutex<Vec<Var>> = Mutex::new(vec![]);
     static ref ENV: Mutex<Env> = Mutex::new(Env::new(None));
     static ref STRLABEL: Mutex<usize> = Mutex::new(0);
+    static ref STATICLABEL: Mutex<usize> = Mutex::new(0);
     static ref STACKSIZE: Mutex<usize> = Mutex::new(0);
+    // The number of named parameters of the function being walked if
+    // it is variadic.
+    static ref VA_PARAMS: Mutex<Option<usize>> = Mutex::new(None);
+    // The return type of the function being walked.
+    static ref RETURN_TY: Mutex<Type> = Mutex::new(Type::default());
 }
 
 #[derive(Debug, Clone)]
@@ -80,36 +95,565 @@ fn find_var(name: &str) -> Option<Var> {
     }
 }
 
+// Reserves space for a local variable of type `ty` in the current
+// stack frame and returns its offset.
+fn alloc_local(ty: &Type) -> usize {
+    let stacksize = *STACKSIZE.lock().unwrap();
+    *STACKSIZE.lock().unwrap() = roundup(stacksize, ty.align);
+    *STACKSIZE.lock().unwrap() += ty.size;
+    *STACKSIZE.lock().unwrap()
+}
+
+fn is_struct(ty: &Type) -> bool {
+    matches!(ty.ty, Ctype::Struct(_, _, _))
+}
+
+// A struct type is only compatible with itself, that is with a type
+// from the same declaration, whatever their members.
+fn struct_compatible(a: &Type, b: &Type) -> bool {
+    match (&a.ty, &b.ty) {
+        (Ctype::Struct(_, _, a), Ctype::Struct(_, _, b)) => a == b,
+        _ => !is_struct(a) && !is_struct(b),
+    }
+}
+
+fn is_aggregate(ty: &Type) -> bool {
+    is_struct(ty) || matches!(ty.ty, Ctype::Ary(_, _))
+}
+
+fn is_long(ty: &Type) -> bool {
+    matches!(ty.ty, Ctype::Long)
+}
+
+fn is_bool(ty: &Type) -> bool {
+    matches!(ty.ty, Ctype::Bool)
+}
+
+fn is_scalar(ty: &Type) -> bool {
+    !is_aggregate(ty) && !matches!(ty.ty, Ctype::Func(_, _, _))
+}
+
+// Converts a walked expression to the scalar type `ty`. Only a
+// conversion to or from long, which takes two words, one to a char,
+// which is truncated to 8 bits, and one to _Bool, which is 0 or 1,
+// change the value. A struct only converts to the same struct type.
+fn convert(node: Node, ty: &Type) -> Node {
+    if !struct_compatible(&node.ty, ty) {
+        panic!("incompatible types");
+    }
+    if !is_scalar(&node.ty) || !is_scalar(ty) {
+        return node;
+    }
+    let truncates = (ty.is_char() && !(node.ty.is_char() && node.ty.is_unsigned == ty.is_unsigned))
+        || (is_bool(ty) && !is_bool(&node.ty));
+    if !truncates && is_long(&node.ty) == is_long(ty) {
+        return node;
+    }
+    let mut cast = Node::new(NodeType::Cast(Box::new(node)));
+    cast.ty = Box::new(ty.clone());
+    cast.ty.is_const = false;
+    cast
+}
+
+// The integer promotion, which converts a char or a _Bool to int. Its
+// value already fits in an int, so only the type changes.
+fn promote(node: Node) -> Node {
+    if !node.ty.is_char() && !is_bool(&node.ty) {
+        return node;
+    }
+    let mut cast = Node::new(NodeType::Cast(Box::new(node)));
+    cast.ty = Box::new(Type::int_ty());
+    cast
+}
+
+// The usual arithmetic conversions, which promote both operands and
+// convert them to long if either is one. The result is unsigned if a
+// long operand is.
+fn arith_conv(lhs: Node, rhs: Node) -> (Node, Node) {
+    let (lhs, rhs) = (promote(lhs), promote(rhs));
+    if !is_long(&lhs.ty) && !is_long(&rhs.ty) {
+        return (lhs, rhs);
+    }
+    let mut ty = Type::long_ty();
+    ty.is_unsigned =
+        (is_long(&lhs.ty) && lhs.ty.is_unsigned) || (is_long(&rhs.ty) && rhs.ty.is_unsigned);
+    (convert(lhs, &ty), convert(rhs, &ty))
+}
+
+// Lowers the compound assignment `lhs op= rhs` to `lhs = lhs op rhs`,
+// computed in the common type of both sides and converted back to the
+// type of lhs. It is used for a long, which has no read-modify-write
+// instruction, and where the common type changes the result. The
+// address of an lvalue other than a variable is evaluated once, into a
+// temporary pointer.
+fn lower_assign(op: TokenType, lhs: Node, rhs: Node) -> Node {
+    let ty = lhs.ty.clone();
+    let (target, set_tmp) = match lhs.op {
+        NodeType::Lvar(_) | NodeType::Gvar(_, _, _) => (lhs, None),
+        _ => {
+            let ptr_ty = Box::new(Type::ptr_to(ty.clone()));
+            let mut tmp = Node::new(NodeType::Lvar(Scope::Local(alloc_local(&ptr_ty))));
+            tmp.ty = ptr_ty.clone();
+            let mut addr = Node::new(NodeType::Addr(Box::new(lhs)));
+            addr.ty = ptr_ty.clone();
+            let mut set_tmp = Node::new_binop(TokenType::Equal, tmp.clone(), addr);
+            set_tmp.ty = ptr_ty;
+            let mut target = Node::new(NodeType::Deref(Box::new(tmp)));
+            target.ty = ty.clone();
+            (target, Some(set_tmp))
+        }
+    };
+
+    // A shift count is an int whatever the type shifted.
+    let (lhs, rhs) = match op {
+        TokenType::SHL | TokenType::SHR => (promote(target.clone()), rhs),
+        _ => arith_conv(target.clone(), rhs),
+    };
+    let val_ty = match op {
+        TokenType::SHL | TokenType::SHR => lhs.ty.clone(),
+        _ if rhs.ty.is_unsigned => rhs.ty.clone(),
+        _ => lhs.ty.clone(),
+    };
+    let mut val = Node::new_binop(op, lhs, rhs);
+    val.ty = val_ty;
+    let mut assign = Node::new_binop(TokenType::Equal, target, convert(val, &ty));
+    assign.ty = ty.clone();
+    match set_tmp {
+        Some(set_tmp) => {
+            let mut node = Node::new_binop(TokenType::Comma, set_tmp, assign);
+            node.ty = ty;
+            node
+        }
+        None => assign,
+    }
+}
+
+// Lowers `x++` of a long to `(x += 1) - 1`, and `x--` likewise.
+fn long_post_inc(expr: Node, op: TokenType, undo: TokenType) -> Node {
+    let ty = expr.ty.clone();
+    let mut one = Node::new_num(1);
+    one.ty = ty.clone();
+    let mut node = Node::new_binop(undo, lower_assign(op, expr, one.clone()), one);
+    node.ty = ty;
+    node
+}
+
 fn maybe_decay(base: Node, decay: bool) -> Node {
     if !decay {
         return base;
     }
 
-    if let Ctype::Ary(ary_of, _) = base.ty.ty.clone() {
-        let mut node = Node::new(NodeType::Addr(Box::new(base)));
-        node.ty = Box::new(Type::ptr_to(ary_of.clone()));
-        node
-    } else {
-        base
+    match base.ty.ty.clone() {
+        Ctype::Ary(ary_of, _) => {
+            let mut node = Node::new(NodeType::Addr(Box::new(base)));
+            node.ty = Box::new(Type::ptr_to(ary_of.clone()));
+            node
+        }
+        // A function designator is converted to a pointer to function.
+        Ctype::Func(_, _, _) => {
+            let ty = Box::new(Type::ptr_to(base.ty.clone()));
+            let mut node = Node::new(NodeType::Addr(Box::new(base)));
+            node.ty = ty;
+            node
+        }
+        _ => base,
     }
 }
 
-fn check_lval(node: &Node) {
+fn is_lval(node: &Node) -> bool {
     let op = &node.op;
-    if !matches!(op, NodeType::Lvar(_))
-        && !matches!(op, NodeType::Gvar(_, _, _))
-        && !matches!(op, NodeType::Deref(_))
-        && !matches!(op, NodeType::Dot(_, _, _))
-    {
+    matches!(op, NodeType::Lvar(_))
+        || matches!(op, NodeType::Gvar(_, _, _))
+        || matches!(op, NodeType::Deref(_))
+        || matches!(op, NodeType::Dot(_, _, _))
+}
+
+// Checks that `node` may be written to.
+fn check_lval(node: &Node) {
+    if !is_lval(node) {
         panic!("not an lvalue: {:?}", node.op);
     }
+    if node.ty.is_const {
+        panic!("assignment of read-only location");
+    }
+}
+
+fn is_char_ary(ty: &Type) -> bool {
+    match ty.ty {
+        Ctype::Ary(ref ary_of, _) => matches!(ary_of.ty, Ctype::Char),
+        _ => false,
+    }
+}
+
+// Flattens the initializer of an object of type `ty` placed at word
+// `offset` into (offset, expr) pairs, one per initialized scalar.
+// Returns the number of elements read, which gives the length of
+// arrays declared as `T x[] = ...`.
+fn flatten_init(ty: &Type, init: Node, offset: usize, out: &mut Vec<(usize, Node)>) -> usize {
+    match init.op {
+        NodeType::Str(ref data, _) if is_char_ary(ty) => {
+            let mut len = 0;
+            for c in data.chars().chain(Some('\0')) {
+                if let Ctype::Ary(_, n) = ty.ty {
+                    if n != 0 && len == n {
+                        break;
+                    }
+                }
+                out.push((offset + len, Node::new_int(c as i32)));
+                len += 1;
+            }
+            len
+        }
+        NodeType::InitList(items) => {
+            let mut items = VecDeque::from(items);
+            let len = flatten_list(ty, &mut items, offset, out, true);
+            if !items.is_empty() {
+                panic!("excess elements in initializer");
+            }
+            len
+        }
+        // The rest of a designator list such as `.pos.x = 1`, which
+        // initializes a member of the object selected so far.
+        NodeType::MemberDesig(_, _) | NodeType::IndexDesig(_, _) => {
+            if !is_aggregate(ty) {
+                panic!("designator in initializer for scalar type");
+            }
+            flatten_list(ty, &mut VecDeque::from(vec![init]), offset, out, true)
+        }
+        _ => {
+            if is_aggregate(ty) {
+                panic!("invalid initializer: {:?}", init.op);
+            }
+            // The value is converted to the type of the scalar it
+            // initializes, which tells how many words it takes.
+            let mut cast = Node::new(NodeType::Cast(Box::new(init)));
+            cast.ty = Box::new(ty.clone());
+            out.push((offset, cast));
+            1
+        }
+    }
+}
+
+fn is_designator(node: &Node) -> bool {
+    matches!(node.op, NodeType::MemberDesig(_, _)) || matches!(node.op, NodeType::IndexDesig(_, _))
+}
+
+// Reads as many initializers from `items` as the object of type `ty`
+// takes, so that braces around nested aggregates may be omitted.
+// A designator moves to the element or member it names and the
+// following initializers continue from there; one found where braces
+// were omitted belongs to an enclosing list.
+fn flatten_list(
+    ty: &Type,
+    items: &mut VecDeque<Node>,
+    offset: usize,
+    out: &mut Vec<(usize, Node)>,
+    braced: bool,
+) -> usize {
+    match ty.ty {
+        Ctype::Ary(ref ary_of, len) => {
+            let mut i = 0;
+            let mut n = 0;
+            while let Some(item) = items.front() {
+                if is_designator(item) {
+                    if !braced {
+                        break;
+                    }
+                    match items.pop_front().unwrap().op {
+                        NodeType::IndexDesig(index, init) => {
+                            if len != 0 && index >= len {
+                                panic!("array index in initializer exceeds array bounds");
+                            }
+                            i = index;
+                            items.push_front(*init);
+                        }
+                        NodeType::MemberDesig(name, _) => {
+                            panic!("field name not in record or union initializer: {}", name)
+                        }
+                        _ => unreachable!(),
+                    }
+                } else if len != 0 && i == len {
+                    break;
+                }
+                flatten_elem(ary_of, items, offset + i * ary_of.size, out);
+                i += 1;
+                n = n.max(i);
+            }
+            n
+        }
+        Ctype::Struct(ref members, is_union, _) => {
+            let mut i = 0;
+            while let Some(item) = items.front() {
+                if is_designator(item) {
+                    if !braced {
+                        break;
+                    }
+                    match items.pop_front().unwrap().op {
+                        NodeType::MemberDesig(name, init) => {
+                            i = members
+                                .iter()
+                                .position(|m| match m.op {
+                                    NodeType::Vardef(ref m_name, _, _) => *m_name == name,
+                                    _ => false,
+                                })
+                                .unwrap_or_else(|| panic!("member missing: {}", name));
+                            items.push_front(*init);
+                        }
+                        NodeType::IndexDesig(_, _) => {
+                            panic!("array index in non-array initializer")
+                        }
+                        _ => unreachable!(),
+                    }
+                } else if i == members.len() {
+                    break;
+                }
+                if let NodeType::Vardef(_, _, Scope::Local(m_offset)) = members[i].op {
+                    flatten_elem(&members[i].ty, items, offset + m_offset, out);
+                }
+                i += 1;
+                // Only one member of a union is initialized.
+                if is_union {
+                    break;
+                }
+            }
+            1
+        }
+        _ => {
+            if let Some(item) = items.pop_front() {
+                flatten_init(ty, item, offset, out);
+            }
+            1
+        }
+    }
+}
+
+fn flatten_elem(
+    ty: &Type,
+    items: &mut VecDeque<Node>,
+    offset: usize,
+    out: &mut Vec<(usize, Node)>,
+) {
+    let item = items.front().unwrap();
+    let is_braced = matches!(item.op, NodeType::InitList(_))
+        || (matches!(item.op, NodeType::Str(_, _)) && is_char_ary(ty))
+        || is_designator(item);
+
+    if is_aggregate(ty) && !is_braced {
+        flatten_list(ty, items, offset, out, false);
+    } else {
+        let item = items.pop_front().unwrap();
+        flatten_init(ty, item, offset, out);
+    }
+}
+
+// Evaluates the address an initializer of a global variable points to.
+fn eval_addr(node: &Node) -> Option<Data> {
+    match node.op {
+        NodeType::Gvar(ref name, _, _) => Some(Data::Addr(name.clone(), 0)),
+        NodeType::Dot(ref expr, _, offset) => match eval_addr(expr)? {
+            Data::Addr(name, off) => Some(Data::Addr(name, off + offset as i32)),
+            Data::Num(_) => unreachable!(),
+        },
+        NodeType::Deref(ref expr) => eval_data(expr),
+        _ => None,
+    }
+}
+
+// Evaluates an initializer of a global variable, which must be either
+// an integer constant or an address constant.
+fn eval_data(node: &Node) -> Option<Data> {
+    if let Some(val) = node.eval() {
+        return Some(Data::Num(val));
+    }
+
+    match node.op {
+        NodeType::Addr(ref expr) => eval_addr(expr),
+        NodeType::Cast(ref expr) => eval_data(expr),
+        NodeType::BinOp(ref op @ TokenType::Plus, ref lhs, ref rhs)
+        | NodeType::BinOp(ref op @ TokenType::Minus, ref lhs, ref rhs) => {
+            match (eval_data(lhs)?, rhs.eval()?) {
+                (Data::Addr(name, off), val) if *op == TokenType::Plus => {
+                    Some(Data::Addr(name, off + val))
+                }
+                (Data::Addr(name, off), val) => Some(Data::Addr(name, off - val)),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// Names in the emitted assembly of objects with internal linkage.
+// They are prefixed so as not to collide with the labels of the
+// standard library, and static locals are numbered as several
+// functions may have one of the same name.
+fn static_label(name: &str) -> String {
+    format!("__static_{}", name)
+}
+
+fn static_local_label(name: &str) -> String {
+    let label = format!("__static{}_{}", *STATICLABEL.lock().unwrap(), name);
+    *STATICLABEL.lock().unwrap() += 1;
+    label
+}
+
+// Flattens the initializer of an object of type `ty`, completing the
+// length of its type if it is an array of unknown length.
+fn flatten_and_complete(ty: &mut Type, init: Node) -> Vec<(usize, Node)> {
+    let mut inits = vec![];
+    let len = flatten_init(ty, init, 0, &mut inits);
+    if let Ctype::Ary(ary_of, 0) = ty.ty.clone() {
+        *ty = Type::ary_of(ary_of, len);
+    }
+    inits
+}
+
+// Computes the initial contents of a global variable.
+fn global_init(ty: &mut Type, init: Node) -> Vec<Data> {
+    let inits = flatten_and_complete(ty, init);
+    let mut data = vec![Data::Num(0); ty.size];
+    for (offset, expr) in inits {
+        let expr = walk(expr, true);
+        let val = eval_data(&expr).unwrap_or_else(|| panic!("initializer element is not constant"));
+        match val {
+            Data::Num(val) if is_long(&expr.ty) => {
+                data[offset] = Data::Num(val & 0xFFFF);
+                data[offset + 1] = Data::Num((val >> 16) & 0xFFFF);
+            }
+            val => data[offset] = val,
+        }
+    }
+    data
+}
+
+// Lowers the initializer of a local aggregate to assignments to each of
+// its words, the ones not given being zero. The constant words of a
+// large object are copied from an anonymous read-only global instead.
+fn local_init(ty: &Type, offset: usize, inits: Vec<(usize, Node)>) -> Vec<Node> {
+    let mut words: Vec<Option<Node>> = vec![None; ty.size];
+    for (off, expr) in inits {
+        let expr = walk(expr, true);
+        if !is_long(&expr.ty) {
+            words[off] = Some(expr);
+            continue;
+        }
+        // A long constant is split into its words. Any other long is
+        // stored as a whole, and its high word is marked by a Null.
+        if let Some(val) = expr.eval() {
+            words[off] = Some(Node::new_int(val & 0xFFFF));
+            words[off + 1] = Some(Node::new_int((val >> 16) & 0xFFFF));
+        } else {
+            words[off] = Some(expr);
+            words[off + 1] = Some(Node::new(NodeType::Null));
+        }
+    }
+
+    let mut stmts = vec![];
+    if ty.size >= INIT_COPY_MIN_WORDS {
+        let mut data = vec![];
+        for word in words.iter_mut() {
+            match word.as_ref().map(eval_data) {
+                Some(Some(val)) => {
+                    data.push(val);
+                    *word = None;
+                }
+                _ => data.push(Data::Num(0)),
+            }
+        }
+
+        let name = format!("init{}", *STRLABEL.lock().unwrap());
+        *STRLABEL.lock().unwrap() += 1;
+        let mut var_ty = Box::new(ty.clone());
+        var_ty.is_const = true;
+        let mut var = Var::new_global(var_ty.clone(), name.clone(), "".into(), ty.size, false);
+        var.init = data;
+        GLOBALS.lock().unwrap().push(var);
+
+        let mut lhs = Node::new(NodeType::Lvar(Scope::Local(offset)));
+        lhs.ty = Box::new(ty.clone());
+        let mut rhs = Node::new(NodeType::Gvar(name, "".into(), ty.size));
+        rhs.ty = var_ty;
+        stmts.push(init_stmt(ty, lhs, rhs));
+    } else {
+        for word in words.iter_mut() {
+            if word.is_none() {
+                *word = Some(Node::new_int(0));
+            }
+        }
+    }
+
+    for (i, word) in words.into_iter().enumerate() {
+        match word {
+            Some(Node {
+                op: NodeType::Null, ..
+            })
+            | None => (),
+            Some(expr) => {
+                let ty = if is_long(&expr.ty) {
+                    Type::long_ty()
+                } else {
+                    Type::int_ty()
+                };
+                let mut lhs = Node::new(NodeType::Lvar(Scope::Local(offset - i)));
+                lhs.ty = Box::new(ty.clone());
+                stmts.push(init_stmt(&ty, lhs, expr));
+            }
+        }
+    }
+    stmts
+}
+
+// Builds an assignment of a walked expression, which skips the checks
+// of assignments, as a const object may be initialized.
+fn init_stmt(ty: &Type, lhs: Node, rhs: Node) -> Node {
+    let mut assign = Node::new_binop(TokenType::Equal, lhs, rhs);
+    assign.ty = Box::new(ty.clone());
+    Node::new(NodeType::ExprStmt(Box::new(assign)))
+}
+
+// Walks the arguments of a call, converting the ones with a declared
+// parameter to its type.
+fn walk_args(args: Vec<Node>, params: &[Type]) -> Vec<Node> {
+    args.into_iter()
+        .enumerate()
+        .map(|(i, arg)| match params.get(i) {
+            Some(ty) => convert(walk(arg, true), ty),
+            None => walk(arg, true),
+        })
+        .collect()
+}
+
+// A call to a function returning a struct passes the address of a
+// temporary for the result as the first argument.
+fn ret_ptr_arg(ty: &Type, args: &mut Vec<Node>) {
+    if !is_struct(ty) {
+        return;
+    }
+    let mut tmp = Node::new(NodeType::Lvar(Scope::Local(alloc_local(ty))));
+    tmp.ty = Box::new(ty.clone());
+    let mut addr = Node::new(NodeType::Addr(Box::new(tmp)));
+    addr.ty = Box::new(Type::ptr_to(Box::new(ty.clone())));
+    args.insert(0, addr);
+}
+
+// Declares a global variable under `name` in the current scope and
+// evaluates its initializer. The variable is already visible in its
+// own initializer.
+fn add_global(name: String, mut var: Var, init: Option<Box<Node>>) {
+    ENV.lock().unwrap().vars.insert(name.clone(), var.clone());
+    if let Some(init) = init {
+        var.init = global_init(&mut var.ty, *init);
+        var.scope = Scope::Global(String::new(), var.ty.size, false);
+        ENV.lock().unwrap().vars.insert(name, var.clone());
+    }
+    GLOBALS.lock().unwrap().push(var);
 }
 
 fn walk(mut node: Node, decay: bool) -> Node {
     use self::NodeType::*;
     let op = node.op.clone();
     match op {
-        Num(_) | Null | Break | Inchar => (),
+        Num(_) | Null | Break | Continue | Goto(_) | Inchar => (),
         Str(data, len) => {
             // Quoted from 9cc
             // > A string literal is converted to a reference to an anonymous
@@ -143,23 +687,85 @@ fn walk(mut node: Node, decay: bool) -> Node {
                 panic!("undefined variable: {}", name);
             }
         }
-        Vardef(name, init_may, _) => {
-            let stacksize = *STACKSIZE.lock().unwrap();
-            *STACKSIZE.lock().unwrap() = roundup(stacksize, node.ty.align);
-            *STACKSIZE.lock().unwrap() += node.ty.size;
-            let offset = *STACKSIZE.lock().unwrap();
+        Vardef(name, mut init_may, _) => {
+            // Aggregates and braced initializers are flattened first, as
+            // they may complete the length of an array.
+            let mut inits = None;
+            if let Some(init) = init_may.take() {
+                if matches!(init.op, InitList(_)) || matches!(node.ty.ty, Ctype::Ary(_, _)) {
+                    inits = Some(flatten_and_complete(&mut node.ty, *init));
+                } else {
+                    init_may = Some(init);
+                }
+            }
 
+            let offset = alloc_local(&node.ty);
             ENV.lock().unwrap().vars.insert(
                 name.clone(),
                 Var::new(node.ty.clone(), name.clone(), Scope::Local(offset)),
             );
 
+            if let Some(inits) = inits {
+                let mut stmts = vec![Node::new(Vardef(name, None, Scope::Local(offset)))];
+                stmts[0].ty = node.ty.clone();
+                stmts.append(&mut local_init(&node.ty, offset, inits));
+                return Node::new(VecStmt(stmts));
+            }
+
             let mut init = None;
             if let Some(init2) = init_may {
-                init = Some(Box::new(walk(*init2, true)));
+                init = Some(Box::new(convert(walk(*init2, true), &node.ty)));
             }
             node.op = Vardef(name, init, Scope::Local(offset));
         }
+        // A static local variable is a global variable only visible in
+        // its scope.
+        Static(def) => {
+            if let Vardef(name, init, _) = def.op {
+                let size = def.ty.size;
+                let var =
+                    Var::new_global(def.ty, static_local_label(&name), "".into(), size, false);
+                add_global(name, var, init);
+                return Node::new(Null);
+            }
+            panic!("static function in block scope");
+        }
+        // A compound literal is an unnamed object, which has static
+        // storage at file scope and is a local variable initialized where
+        // it appears otherwise.
+        CompLit(init) => {
+            if ENV.lock().unwrap().next.is_none() {
+                let name = format!("lit{}", *STRLABEL.lock().unwrap());
+                *STRLABEL.lock().unwrap() += 1;
+                let mut var = Var::new_global(node.ty, name.clone(), "".into(), 0, false);
+                var.init = global_init(&mut var.ty, *init);
+                var.scope = Scope::Global("".into(), var.ty.size, false);
+
+                let mut ret = Node::new(Gvar(name, "".into(), var.ty.size));
+                ret.ty = var.ty.clone();
+                GLOBALS.lock().unwrap().push(var);
+                return maybe_decay(ret, decay);
+            }
+
+            let inits = flatten_and_complete(&mut node.ty, *init);
+            let offset = alloc_local(&node.ty);
+            let ptr_ty = Box::new(Type::ptr_to(node.ty.clone()));
+
+            // *(init, ..., &tmp)
+            let mut tmp = Node::new(Lvar(Scope::Local(offset)));
+            tmp.ty = node.ty.clone();
+            let mut expr = Node::new(Addr(Box::new(tmp)));
+            expr.ty = ptr_ty.clone();
+            for stmt in local_init(&node.ty, offset, inits).into_iter().rev() {
+                if let ExprStmt(assign) = stmt.op {
+                    expr = Node::new_binop(TokenType::Comma, *assign, expr);
+                    expr.ty = ptr_ty.clone();
+                }
+            }
+            let mut ret = Node::new(Deref(Box::new(expr)));
+            ret.ty = node.ty;
+            return maybe_decay(ret, decay);
+        }
         If(mut cond, mut then, els_may) => {
             cond = Box::new(walk(*cond, true));
             then = Box::new(walk(*then, true));
@@ -173,8 +779,9 @@ fn walk(mut node: Node, decay: bool) -> Node {
             cond = Box::new(walk(*cond, true));
             then = Box::new(walk(*then, true));
             els = Box::new(walk(*els, true));
-            node.ty = then.ty.clone();
-            node.op = Ternary(cond, then, els);
+            let (then2, els2) = arith_conv(*then, *els);
+            node.ty = then2.ty.clone();
+            node.op = Ternary(cond, Box::new(then2), Box::new(els2));
         }
         For(init, cond, inc, body) => {
             let f = |(init, cond, inc, body)| -> (Node, Node, Node, Node) {
@@ -196,10 +803,40 @@ fn walk(mut node: Node, decay: bool) -> Node {
         DoWhile(body, cond) => {
             node.op = DoWhile(Box::new(walk(*body, true)), Box::new(walk(*cond, true)));
         }
+        Switch(cond, body, cases, has_default) => {
+            let cond = promote(walk(*cond, true));
+
+            // The case values are converted to the type of the
+            // controlling expression, so `case -1:` and `case 65535:`
+            // are the same label in a 16-bit switch.
+            let mut vals: Vec<i32> = vec![];
+            for val in cases {
+                let val = if is_long(&cond.ty) {
+                    val
+                } else if cond.ty.is_unsigned {
+                    val as u16 as i32
+                } else {
+                    val as i16 as i32
+                };
+                if vals.contains(&val) {
+                    panic!("duplicate case value: {}", val);
+                }
+                vals.push(val);
+            }
+            node.op = Switch(
+                Box::new(cond),
+                Box::new(walk(*body, true)),
+                vals,
+                has_default,
+            );
+        }
+        Case(idx, stmt) => node.op = Case(idx, Box::new(walk(*stmt, true))),
+        Label(name, stmt) => node.op = Label(name, Box::new(walk(*stmt, true))),
+        Default(stmt) => node.op = Default(Box::new(walk(*stmt, true))),
         Dot(mut expr, name, _) => {
             expr = Box::new(walk(*expr, true));
             let offset;
-            if let Ctype::Struct(ref members) = expr.ty.ty {
+            if let Ctype::Struct(ref members, _, _) = expr.ty.ty {
                 if members.is_empty() {
                     panic!("incomplete type");
                 }
@@ -216,6 +853,8 @@ fn walk(mut node: Node, decay: bool) -> Node {
                 if let Some(m) = m_may {
                     if let NodeType::Vardef(_, _, Scope::Local(offset2)) = m.op {
                         node.ty = m.ty.clone();
+                        // The members of a const struct are const.
+                        node.ty.is_const |= expr.ty.is_const;
                         offset = offset2;
                     } else {
                         unreachable!()
@@ -245,7 +884,12 @@ fn walk(mut node: Node, decay: bool) -> Node {
                     }
 
                     if matches!(lhs.ty.ty, Ctype::Ptr(_)) {
+                        rhs = Box::new(convert(*rhs, &Type::int_ty()));
                         rhs = Box::new(Node::scale_ptr(rhs, &lhs.ty));
+                    } else {
+                        let (lhs2, rhs2) = arith_conv(*lhs, *rhs);
+                        lhs = Box::new(lhs2);
+                        rhs = Box::new(rhs2);
                     }
 
                     node.op = BinOp(token_type, lhs.clone(), rhs);
@@ -257,48 +901,133 @@ fn walk(mut node: Node, decay: bool) -> Node {
                     rhs = Box::new(walk(*rhs, true));
 
                     if matches!(lhs.ty.ty, Ctype::Ptr(_)) {
+                        rhs = Box::new(convert(*rhs, &Type::int_ty()));
                         rhs = Box::new(Node::scale_ptr(rhs, &lhs.ty));
+                    } else if is_long(&lhs.ty) {
+                        let op = if token_type == AddEQ { Plus } else { Minus };
+                        return lower_assign(op, *lhs, *rhs);
+                    } else {
+                        rhs = Box::new(convert(*rhs, &lhs.ty));
                     }
                     node.op = BinOp(token_type, lhs.clone(), rhs);
                     node.ty = lhs.ty;
                 }
-                Equal | MulEQ | DivEQ | ModEQ | ShlEQ | ShrEQ | BitandEQ | XorEQ | BitorEQ => {
+                Equal => {
                     lhs = Box::new(walk(*lhs, false));
                     check_lval(&*lhs);
-                    node.op = BinOp(token_type, lhs.clone(), Box::new(walk(*rhs, true)));
+                    rhs = Box::new(walk(*rhs, true));
+                    if !struct_compatible(&lhs.ty, &rhs.ty) {
+                        panic!("incompatible types in assignment");
+                    }
+                    rhs = Box::new(convert(*rhs, &lhs.ty));
+                    node.op = BinOp(token_type, lhs.clone(), rhs);
                     node.ty = lhs.ty;
                 }
-                _ => {
-                    lhs = Box::new(walk(*lhs, true));
+                MulEQ | DivEQ | ModEQ | ShlEQ | ShrEQ | BitandEQ | XorEQ | BitorEQ => {
+                    lhs = Box::new(walk(*lhs, false));
+                    check_lval(&*lhs);
                     rhs = Box::new(walk(*rhs, true));
+                    // A shift count is an int whatever the type shifted.
+                    if token_type == ShlEQ || token_type == ShrEQ {
+                        rhs = Box::new(convert(*rhs, &Type::int_ty()));
+                    }
+                    // A division whose common type is wider than lhs or
+                    // unsigned is computed in that type. The other
+                    // operations give the same word in the type of lhs.
+                    let rhs_ty = promote((*rhs).clone()).ty;
+                    let widens = lhs.ty.is_char()
+                        || is_long(&rhs_ty)
+                        || (rhs_ty.is_unsigned && !lhs.ty.is_unsigned);
+                    if is_long(&lhs.ty) || (widens && (token_type == DivEQ || token_type == ModEQ))
+                    {
+                        let op = match token_type {
+                            MulEQ => Mul,
+                            DivEQ => Div,
+                            ModEQ => Mod,
+                            ShlEQ => SHL,
+                            ShrEQ => SHR,
+                            BitandEQ => And,
+                            XorEQ => Hat,
+                            _ => VerticalBar,
+                        };
+                        return lower_assign(op, *lhs, *rhs);
+                    }
+                    if token_type != ShlEQ && token_type != ShrEQ {
+                        rhs = Box::new(convert(*rhs, &lhs.ty));
+                    }
                     node.op = BinOp(token_type, lhs.clone(), rhs);
                     node.ty = lhs.ty;
                 }
+                _ => {
+                    lhs = Box::new(walk(*lhs, true));
+                    rhs = Box::new(walk(*rhs, true));
+                    match token_type {
+                        SHL | SHR => {
+                            lhs = Box::new(promote(*lhs));
+                            rhs = Box::new(convert(*rhs, &Type::int_ty()));
+                        }
+                        Logand | Logor | Comma => (),
+                        _ => {
+                            let (lhs2, rhs2) = arith_conv(*lhs, *rhs);
+                            lhs = Box::new(lhs2);
+                            rhs = Box::new(rhs2);
+                        }
+                    }
+                    node.ty = match token_type {
+                        LeftAngleBracket | LE | RightAngleBracket | GE | EQ | NE | Logand
+                        | Logor => Box::new(Type::int_ty()),
+                        SHL | SHR => lhs.ty.clone(),
+                        Comma => rhs.ty.clone(),
+                        // If either operand is unsigned, so is the result.
+                        _ if rhs.ty.is_unsigned => rhs.ty.clone(),
+                        _ => lhs.ty.clone(),
+                    };
+                    node.op = BinOp(token_type, lhs, rhs);
+                }
             }
         }
         PostInc(mut expr) => {
             expr = Box::new(walk(*expr, true));
+            check_lval(&expr);
+            if is_long(&expr.ty) {
+                return long_post_inc(*expr, TokenType::Plus, TokenType::Minus);
+            }
             node.ty = expr.ty.clone();
             node.op = PostInc(expr);
         }
         PostDec(mut expr) => {
             expr = Box::new(walk(*expr, true));
+            check_lval(&expr);
+            if is_long(&expr.ty) {
+                return long_post_inc(*expr, TokenType::Minus, TokenType::Plus);
+            }
             node.ty = expr.ty.clone();
             node.op = PostDec(expr);
         }
         Neg(mut expr) => {
-            expr = Box::new(walk(*expr, true));
+            expr = Box::new(promote(walk(*expr, true)));
             node.ty = expr.ty.clone();
             node.op = Neg(expr);
         }
         Exclamation(mut expr) => {
             expr = Box::new(walk(*expr, true));
-            node.ty = expr.ty.clone();
+            node.ty = Box::new(Type::int_ty());
             node.op = Exclamation(expr);
         }
-        Addr(mut expr) => {
+        Cast(mut expr) => {
             expr = Box::new(walk(*expr, true));
-            check_lval(&*expr);
+            if matches!(node.ty.ty, Ctype::Struct(_, _, _))
+                || matches!(expr.ty.ty, Ctype::Struct(_, _, _))
+            {
+                panic!("invalid cast");
+            }
+            node.op = Cast(expr);
+        }
+        Addr(mut expr) => {
+            expr = Box::new(walk(*expr, false));
+            if !is_lval(&expr) {
+                panic!("not an lvalue: {:?}", expr.op);
+            }
             node.ty = Box::new(Type::ptr_to(expr.ty.clone()));
             node.op = Addr(expr);
         }
@@ -312,7 +1041,16 @@ fn walk(mut node: Node, decay: bool) -> Node {
             node.op = Deref(expr);
             return maybe_decay(node, decay);
         }
-        Return(expr) => node.op = Return(Box::new(walk(*expr, true))),
+        Return(mut expr) => {
+            // A struct is returned by copying it to the object the hidden
+            // parameter points to.
+            if find_var(RET_PTR).is_some() {
+                let ret = Node::new(Deref(Box::new(Node::new(Ident(RET_PTR.into())))));
+                expr = Box::new(Node::new_binop(TokenType::Equal, ret, *expr));
+            }
+            let ty = RETURN_TY.lock().unwrap().clone();
+            node.op = Return(Box::new(convert(walk(*expr, true), &ty)));
+        }
         Outchar(ch, pos) => {
             node.op = Outchar(Box::new(walk(*ch, true)), Box::new(walk(*pos, true)));
         }
@@ -325,20 +1063,64 @@ fn walk(mut node: Node, decay: bool) -> Node {
             expr = Box::new(walk(*expr, false));
             node = Node::new_int(expr.ty.align as i32)
         }
-        Call(name, mut args) => {
+        Call(mut name, mut args, _) => {
+            let mut is_variadic = false;
+            let mut params = vec![];
             if let Some(var) = find_var(&name) {
-                if let Ctype::Func(returning) = var.ty.ty {
-                    node.ty = returning;
-                } else {
-                    eprint!("bad function: {}", name);
+                match var.ty.ty {
+                    Ctype::Func(returning, params2, variadic) => {
+                        node.ty = returning;
+                        name = var.name;
+                        params = params2;
+                        is_variadic = variadic;
+                    }
+                    Ctype::Ptr(_) => {
+                        let callee = Box::new(Node::new(Ident(name)));
+                        node.op = CallPtr(callee, args);
+                        return walk(node, true);
+                    }
+                    _ => eprint!("bad function: {}", name),
                 }
             } else {
                 eprint!("bad function: {}", name);
             }
 
-            args = args.into_iter().map(|arg| walk(arg, true)).collect();
-            node.op = Call(name, args);
+            args = walk_args(args, &params);
+            ret_ptr_arg(&node.ty, &mut args);
+            node.op = Call(name, args, is_variadic);
+        }
+        CallPtr(mut expr, mut args) => {
+            expr = Box::new(walk(*expr, true));
+            let mut params = vec![];
+            if let Ctype::Ptr(ref ptr_to) = expr.ty.ty {
+                if let Ctype::Func(ref returning, ref params2, _) = ptr_to.ty {
+                    node.ty = returning.clone();
+                    params = params2.clone();
+                } else {
+                    panic!("called object is not a function");
+                }
+            } else {
+                panic!("called object is not a function");
+            }
+
+            args = walk_args(args, &params);
+            ret_ptr_arg(&node.ty, &mut args);
+            node.op = CallPtr(expr, args);
+        }
+        VaStart(mut ap, _) => {
+            ap = Box::new(walk(*ap, false));
+            check_lval(&ap);
+            match *VA_PARAMS.lock().unwrap() {
+                Some(nparams) => node.op = VaStart(ap, nparams),
+                None => panic!("va_start used in function with fixed arguments"),
+            }
+        }
+        VaArg(mut ap) => {
+            ap = Box::new(walk(*ap, false));
+            check_lval(&ap);
+            node.op = VaArg(ap);
         }
+        VaEnd(ap) => node.op = VaEnd(Box::new(walk(*ap, true))),
         CompStmt(mut stmts) => {
             let f = |stmts: Vec<Node>| -> Vec<Node> {
                 stmts.into_iter().map(|stmt| walk(stmt, true)).collect()
@@ -363,17 +1145,34 @@ pub fn sema(nodes: Vec<Node>) -> (Vec<Node>, Vec<Var>) {
     let mut new_nodes = vec![];
 
     for mut node in nodes {
-        if let NodeType::Vardef(name, _, Scope::Global(data, len, is_extern)) = node.op {
-            let var = Var::new_global(node.ty, name.clone(), data, len, is_extern);
-            GLOBALS.lock().unwrap().push(var.clone());
-            ENV.lock().unwrap().vars.insert(name, var);
+        let mut is_static = false;
+        if let NodeType::Static(def) = node.op {
+            node = *def;
+            is_static = true;
+        }
+
+        if let NodeType::Vardef(name, init, Scope::Global(data, len, is_extern)) = node.op {
+            let label = if is_static {
+                static_label(&name)
+            } else {
+                name.clone()
+            };
+            let var = Var::new_global(node.ty, label, data, len, is_extern);
+            add_global(name, var, init);
             continue;
         }
 
-        let var;
+        let label;
         match &node.op {
             NodeType::Func(name, _, _, _) | NodeType::Decl(name) => {
-                var = Var::new_global(node.ty.clone(), name.clone(), "".into(), 0, false);
+                // A function declared static keeps internal linkage when
+                // it is defined without the keyword.
+                label = match find_var(name) {
+                    _ if is_static => static_label(name),
+                    Some(var) => var.name,
+                    None => name.clone(),
+                };
+                let var = Var::new_global(node.ty.clone(), label.clone(), "".into(), 0, false);
                 ENV.lock().unwrap().vars.insert(name.clone(), var);
             }
             _ => unreachable!(),
@@ -383,18 +1182,33 @@ pub fn sema(nodes: Vec<Node>) -> (Vec<Node>, Vec<Var>) {
             continue;
         }
 
-        if let NodeType::Func(name, args, body, _) = node.op {
-            let mut args2 = vec![];
-            for arg in args {
-                args2.push(walk(arg, true));
+        if let NodeType::Func(_, mut args, body, _) = node.op {
+            if let Ctype::Func(ref returning, _, is_variadic) = node.ty.ty {
+                if is_struct(returning) {
+                    let mut ret =
+                        Node::new(NodeType::Vardef(RET_PTR.into(), None, Scope::Local(0)));
+                    ret.ty = Box::new(Type::ptr_to(returning.clone()));
+                    args.insert(0, ret);
+                }
+                // va_start skips the words of the named parameters.
+                *VA_PARAMS.lock().unwrap() = if is_variadic {
+                    Some(
+                        args.iter()
+                            .map(|arg| if is_long(&arg.ty) { 2 } else { 1 })
+                            .sum(),
+                    )
+                } else {
+                    None
+                };
+                *RETURN_TY.lock().unwrap() = *returning.clone();
             }
-            let body2 = walk(*body, true);
-            node.op = NodeType::Func(
-                name.clone(),
-                args2,
-                Box::new(body2),
-                *STACKSIZE.lock().unwrap(),
-            );
+
+            let f = |(args, body): (Vec<Node>, Node)| -> (Vec<Node>, Node) {
+                let args = args.into_iter().map(|arg| walk(arg, true)).collect();
+                (args, walk(body, true))
+            };
+            let (args2, body2) = into_new_range((args, *body), Box::new(f));
+            node.op = NodeType::Func(label, args2, Box::new(body2), *STACKSIZE.lock().unwrap());
             *STACKSIZE.lock().unwrap() = 0;
             new_nodes.push(node);
         }
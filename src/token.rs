@@ -11,30 +11,56 @@ pub fn tokenize(data: String, filename: String, ctx: &mut preprocess::Preprocess
     tokenizer.remove_backslash_newline();
     tokenizer.scan(&keyword_map());
 
-    tokenizer.tokens = preprocess::preprocess(tokenizer.tokens, ctx);
+    tokenizer.tokens = preprocess::preprocess(tokenizer.tokens, &tokenizer.filename, ctx);
+    tokenizer.check_invalid();
     tokenizer.strip_newlines_tokens();
     tokenizer.join_string_literals();
     tokenizer.tokens
 }
 
+// Splits a string into tokens without preprocessing it, as when the
+// preprocessor pastes two tokens together.
+pub fn lex(data: String, filename: Rc<String>) -> Vec<Token> {
+    let mut tokenizer = Tokenizer::new(Rc::new(data), filename);
+    tokenizer.scan(&keyword_map());
+    tokenizer.tokens
+}
+
 fn keyword_map() -> HashMap<String, TokenType> {
     let mut map = HashMap::new();
     map.insert("_Alignof".into(), TokenType::Alignof);
+    map.insert("__builtin_va_start".into(), TokenType::VaStart);
+    map.insert("__builtin_va_arg".into(), TokenType::VaArg);
+    map.insert("__builtin_va_end".into(), TokenType::VaEnd);
     map.insert("break".into(), TokenType::Break);
+    map.insert("case".into(), TokenType::Case);
     map.insert("char".into(), TokenType::Char);
+    map.insert("_Bool".into(), TokenType::Bool);
+    map.insert("long".into(), TokenType::Long);
+    map.insert("const".into(), TokenType::Const);
+    map.insert("continue".into(), TokenType::Continue);
+    map.insert("default".into(), TokenType::Default);
     map.insert("void".into(), TokenType::Void);
+    map.insert("volatile".into(), TokenType::Volatile);
     map.insert("do".into(), TokenType::Do);
     map.insert("else".into(), TokenType::Else);
+    map.insert("enum".into(), TokenType::Enum);
     map.insert("extern".into(), TokenType::Extern);
     map.insert("for".into(), TokenType::For);
+    map.insert("goto".into(), TokenType::Goto);
     map.insert("if".into(), TokenType::If);
     map.insert("int".into(), TokenType::Int);
     map.insert("return".into(), TokenType::Return);
+    map.insert("signed".into(), TokenType::Signed);
     map.insert("_outchar".into(), TokenType::Outchar);
     map.insert("_inchar".into(), TokenType::Inchar);
     map.insert("sizeof".into(), TokenType::Sizeof);
+    map.insert("static".into(), TokenType::Static);
     map.insert("struct".into(), TokenType::Struct);
+    map.insert("switch".into(), TokenType::Switch);
     map.insert("typedef".into(), TokenType::Typedef);
+    map.insert("union".into(), TokenType::Union);
+    map.insert("unsigned".into(), TokenType::Unsigned);
     map.insert("while".into(), TokenType::While);
     map
 }
@@ -51,6 +77,7 @@ pub struct Token {
     pub filename: Rc<String>,
     pub start: usize,
     pub end: usize,
+    pub line_delta: isize, // Set by #line
 }
 
 impl Default for Token {
@@ -61,6 +88,7 @@ impl Default for Token {
             filename: Rc::new("".to_string()),
             start: 0,
             end: 0,
+            line_delta: 0,
             stringize: false,
         }
     }
@@ -78,16 +106,30 @@ impl Token {
     }
 
     pub fn bad_token(&self, msg: &str) -> ! {
-        print_line(&*self.buf, &*self.filename, self.start);
+        print_line(&*self.buf, &*self.filename, self.start, self.line_delta);
         panic!("{}", msg);
     }
 
+    pub fn warn(&self, msg: &str) {
+        eprintln!(
+            "warning at {}:{}: {}",
+            self.filename,
+            self.get_line_number(),
+            msg
+        );
+    }
+
     pub fn tokstr(&self) -> String {
         self.buf[self.start..self.end].iter().collect()
     }
 
     pub fn get_line_number(&self) -> usize {
-        self.buf[..self.end].iter().filter(|c| *c == &'\n').count()
+        let line = self.buf[..self.start]
+            .iter()
+            .filter(|c| *c == &'\n')
+            .count()
+            + 1;
+        (line as isize + self.line_delta) as usize
     }
 
     pub fn is_ident(&self, s: &str) -> bool {
@@ -112,9 +154,11 @@ impl Symbol {
 
 lazy_static! {
     static ref SYMBOLS: Vec<Symbol> = [
+        Symbol::new("...", TokenType::Ellipsis),
         Symbol::new("<<=", TokenType::ShlEQ),
         Symbol::new(">>=", TokenType::ShrEQ),
         Symbol::new("!=", TokenType::NE),
+        Symbol::new("##", TokenType::HashHash),
         Symbol::new("&&", TokenType::Logand),
         Symbol::new("++", TokenType::Inc),
         Symbol::new("--", TokenType::Dec),
@@ -239,9 +283,9 @@ impl Tokenizer {
                         self.tokens.push(t);
                         continue 'outer;
                     }
-                    self.bad_position("Unknown symbol.");
+                    self.invalid("Unknown symbol.", self.pos + 1);
                 }
-                CharacterType::Unknown(_) => self.bad_position("Unknown character type."),
+                CharacterType::Unknown(_) => self.invalid("Unknown character type.", self.pos + 1),
             }
         }
 
@@ -283,10 +327,35 @@ impl Tokenizer {
         }
     }
 
+    // Text that cannot be scanned may be in a group skipped by the
+    // preprocessor, so the error is only reported if its token is
+    // left after preprocessing.
+    fn invalid(&mut self, msg: &'static str, end: usize) {
+        let mut t = self.new_token(TokenType::Invalid(msg));
+        self.pos = end;
+        t.end = self.pos;
+        self.tokens.push(t);
+    }
+
+    fn check_invalid(&self) {
+        for t in self.tokens.iter() {
+            if let TokenType::Invalid(msg) = t.ty {
+                t.bad_token(msg);
+            }
+        }
+    }
+
     fn char_literal(&mut self) {
+        let start = self.pos;
         self.pos += 1;
         let result: char;
-        let c = self.p.get(self.pos).expect("premature end of input");
+        let c = match self.p.get(self.pos) {
+            Some(c) if *c != '\n' => c,
+            _ => {
+                self.pos = start;
+                return self.invalid("unclosed character literal", start + 1);
+            }
+        };
         if c != &'\\' {
             result = *c;
             self.pos += 1;
@@ -302,27 +371,36 @@ impl Tokenizer {
         }
 
         if self.p.get(self.pos) != Some(&'\'') {
-            panic!("unclosed character literal");
+            self.pos = start;
+            return self.invalid("unclosed character literal", start + 1);
         }
 
         let mut t = self.new_token(TokenType::Num(result as u8 as i32));
+        t.start = start;
         self.pos += 1;
-        t.end = self.pos + 1;
+        t.end = self.pos;
         self.tokens.push(t);
     }
 
     fn string_literal(&mut self) {
+        let start = self.pos;
         self.pos += 1;
         let mut sb = String::new();
         let mut len = 0;
         loop {
-            let mut c2 = self.p.get(self.pos + len).expect("PREMATURE end of input");
+            let mut c2 = match self.p.get(self.pos + len) {
+                Some(c) if *c != '\n' => c,
+                _ => {
+                    self.pos = start;
+                    return self.invalid("unclosed string literal", start + 1);
+                }
+            };
             if c2 == &'"' {
                 len += 1;
                 self.pos += len;
                 let mut t = self.new_token(TokenType::Str(sb, len));
                 t.start = self.pos - len - 1;
-                t.end = self.pos + 1;
+                t.end = self.pos;
                 self.tokens.push(t);
                 return;
             }
@@ -383,18 +461,44 @@ impl Tokenizer {
         }
     }
 
+    // A literal has the first of int, unsigned int, long and unsigned
+    // long that can represent it. A decimal one without a `u` suffix
+    // skips the unsigned types, except for an unsigned long, and one
+    // with an `l` suffix skips the int types.
     fn parse_number(&mut self, base: u32) {
-        let mut sum: i32 = 0;
+        let mut sum: i64 = 0;
         let mut len = 0;
         for c in self.p[self.pos..].iter() {
             if let Some(val) = c.to_digit(base) {
-                sum = sum * base as i32 + val as i32;
+                sum = (sum * base as i64 + val as i64) & 0xFFFF_FFFF;
                 len += 1;
             } else {
                 break;
             }
         }
-        let mut t = self.new_token(TokenType::Num(sum as i32));
+
+        let mut has_l = false;
+        let mut has_u = false;
+        while let Some(c) = self.p.get(self.pos + len) {
+            match c {
+                'l' | 'L' => has_l = true,
+                'u' | 'U' => has_u = true,
+                _ => break,
+            }
+            len += 1;
+        }
+
+        let may_be_unsigned = has_u || base != 10;
+        let ty = if !has_l && !has_u && sum <= 0x7FFF {
+            TokenType::Num(sum as i32)
+        } else if !has_l && may_be_unsigned && sum <= 0xFFFF {
+            TokenType::UNum(sum as i32)
+        } else if !has_u && sum <= 0x7FFF_FFFF {
+            TokenType::LongNum(sum as i32, false)
+        } else {
+            TokenType::LongNum(sum as i32, true)
+        };
+        let mut t = self.new_token(ty);
         self.pos += len;
         t.end = self.pos;
         self.tokens.push(t);
@@ -479,14 +583,14 @@ impl Tokenizer {
     }
 
     fn bad_position(&self, msg: &'static str) {
-        print_line(&self.p, &self.filename, self.pos);
+        print_line(&self.p, &self.filename, self.pos, 0);
         panic!("{}", msg);
     }
 }
 
 // Finds a line pointed by a given pointer from the input file
 // to print it out.
-fn print_line(buf: &[char], path: &str, pos: usize) {
+fn print_line(buf: &[char], path: &str, pos: usize, line_delta: isize) {
     let mut p = 0;
     let mut start = 0;
     let mut line = 0;
@@ -506,7 +610,12 @@ fn print_line(buf: &[char], path: &str, pos: usize) {
             continue;
         }
 
-        print!("error at {}:{}:{}\n\n", path, line + 1, col);
+        print!(
+            "error at {}:{}:{}\n\n",
+            path,
+            line as isize + 1 + line_delta,
+            col
+        );
         break;
     }
 
@@ -0,0 +1,279 @@
+; 32-bit long helpers. A long is passed as two words, the low one first,
+; and returned with its low word in r7 and its high word in __lhi.
+
+; __lmul : multiplies two longs
+; in r1, r2 : a
+; in r3, r4 : b
+; out r7, __lhi : a * b
+; internal r5, r6
+__lmul:
+  xor r5, r5, r5
+  xor r6, r6, r6
+
+  __lmul_loop:
+    or r7, r3, r4
+    jz __lmul_rts
+
+    loadn r7, #1
+    and r7, r3, r7
+    jz __lmul_shift
+
+    add r5, r5, r1
+    addc r6, r6, r2
+
+  __lmul_shift:
+    add r1, r1, r1
+    addc r2, r2, r2
+
+    mov r7, r4
+    shiftl0 r7, #15
+    shiftr0 r3, #1
+    or r3, r3, r7
+    shiftr0 r4, #1
+    jmp __lmul_loop
+
+  __lmul_rts:
+    mov r1, r5
+    mov r2, r6
+    jmp __lret
+
+; __ludivmod : divides two unsigned longs
+; in r1, r2 : n
+; in r3, r4 : d
+; out r1, r2 : n / d
+; out r5, r6 : n % d
+; internal r0, r7
+__ludivmod:
+  push r0
+
+  xor r5, r5, r5
+  xor r6, r6, r6
+  loadn r0, #32
+
+  __ludivmod_loop:
+    ; the bit shifted out of the remainder, which is then greater than d
+    loadn r7, #32768
+    and r7, r6, r7
+
+    add r1, r1, r1
+    addc r2, r2, r2
+    addc r5, r5, r5
+    addc r6, r6, r6
+
+    or r7, r7, r7
+    jnz __ludivmod_sub
+    cmp r6, r4
+    jgr __ludivmod_sub
+    jle __ludivmod_next
+    cmp r5, r3
+    jle __ludivmod_next
+
+  __ludivmod_sub:
+    cmp r5, r3
+    jeg __ludivmod_noborrow
+    dec r6
+
+  __ludivmod_noborrow:
+    sub r5, r5, r3
+    sub r6, r6, r4
+    inc r1
+
+  __ludivmod_next:
+    dec r0
+    jnz __ludivmod_loop
+
+  pop r0
+  rts
+
+; __labs : takes the absolute values of two longs
+; in r1, r2 : a
+; in r3, r4 : b
+; out r1, r2 : |a|
+; out r3, r4 : |b|
+; internal r7
+__labs:
+  loadn r7, #32768
+
+  cmp r2, r7
+  jle __labs_b
+  not r1, r1
+  not r2, r2
+  inc r1
+  jnz __labs_b
+  inc r2
+
+  __labs_b:
+    cmp r4, r7
+    jle __labs_rts
+    not r3, r3
+    not r4, r4
+    inc r3
+    jnz __labs_rts
+    inc r4
+
+  __labs_rts:
+    rts
+
+; __ldiv : divides two longs
+; in r1, r2 : a
+; in r3, r4 : b
+; out r7, __lhi : a / b
+; internal r5, r6
+__ldiv:
+  xor r7, r2, r4
+  push r7
+
+  call __labs
+  call __ludivmod
+
+  pop r7
+  jmp __lret_signed
+
+; __lmod : takes the remainder of the division of two longs
+; in r1, r2 : a
+; in r3, r4 : b
+; out r7, __lhi : a % b
+; internal r5, r6
+__lmod:
+  push r2
+
+  call __labs
+  call __ludivmod
+  mov r1, r5
+  mov r2, r6
+
+  pop r7
+  jmp __lret_signed
+
+; __ludiv : divides two unsigned longs
+; in r1, r2 : a
+; in r3, r4 : b
+; out r7, __lhi : a / b
+; internal r5, r6
+__ludiv:
+  call __ludivmod
+  jmp __lret
+
+; __lumod : takes the remainder of the division of two unsigned longs
+; in r1, r2 : a
+; in r3, r4 : b
+; out r7, __lhi : a % b
+; internal r5, r6
+__lumod:
+  call __ludivmod
+  mov r1, r5
+  mov r2, r6
+  jmp __lret
+
+; __lshl : shifts a long left
+; in r1, r2 : a
+; in r3 : count
+; out r7, __lhi : a << count
+__lshl:
+  inc r3
+
+  __lshl_loop:
+    dec r3
+    jz __lret
+
+    add r1, r1, r1
+    addc r2, r2, r2
+    jmp __lshl_loop
+
+; __lshr : shifts an unsigned long right
+; in r1, r2 : a
+; in r3 : count
+; out r7, __lhi : a >> count
+__lshr:
+  inc r3
+
+  __lshr_loop:
+    dec r3
+    jz __lret
+
+    mov r7, r2
+    shiftl0 r7, #15
+    shiftr0 r1, #1
+    or r1, r1, r7
+    shiftr0 r2, #1
+    jmp __lshr_loop
+
+; __lsar : shifts a long right, keeping its sign
+; in r1, r2 : a
+; in r3 : count
+; out r7, __lhi : a >> count
+; internal r4
+__lsar:
+  loadn r4, #32768
+  inc r3
+
+  __lsar_loop:
+    dec r3
+    jz __lret
+
+    mov r7, r2
+    shiftl0 r7, #15
+    shiftr0 r1, #1
+    or r1, r1, r7
+
+    cmp r2, r4
+    jle __lsar_positive
+    shiftr1 r2, #1
+    jmp __lsar_loop
+
+  __lsar_positive:
+    shiftr0 r2, #1
+    jmp __lsar_loop
+
+; __lcmp : compares two longs
+; in r1, r2 : a
+; in r3, r4 : b
+; out r7 : -1, 0 or 1 as a is less than, equal to or greater than b
+__lcmp:
+  loadn r7, #32768
+  xor r2, r2, r7
+  xor r4, r4, r7
+
+; __lucmp : compares two unsigned longs
+; in r1, r2 : a
+; in r3, r4 : b
+; out r7 : -1, 0 or 1 as a is less than, equal to or greater than b
+__lucmp:
+  cmp r2, r4
+  jgr __lucmp_greater
+  jle __lucmp_lesser
+
+  cmp r1, r3
+  jgr __lucmp_greater
+  jle __lucmp_lesser
+
+  xor r7, r7, r7
+  rts
+
+  __lucmp_greater:
+    loadn r7, #1
+    rts
+
+  __lucmp_lesser:
+    loadn r7, #65535
+    rts
+
+; negates the long in r1, r2 if r7 is negative, then returns it
+__lret_signed:
+  loadn r6, #32768
+  cmp r7, r6
+  jle __lret
+
+  not r1, r1
+  not r2, r2
+  inc r1
+  jnz __lret
+  inc r2
+
+; returns the long in r1, r2
+__lret:
+  store __lhi, r2
+  mov r7, r1
+  rts
+
+__lhi : var #1
@@ -1,3 +1,5 @@
+#pragma once
+
 extern int getch(void);
 extern void erase(void);
 
@@ -0,0 +1,9 @@
+#pragma once
+
+// All the arguments of a variadic function are passed on the stack, one
+// word each. A va_list points to the next one.
+typedef int *va_list;
+
+#define va_start(ap, last) __builtin_va_start(ap, last)
+#define va_arg(ap, type) __builtin_va_arg(ap, type)
+#define va_end(ap) __builtin_va_end(ap)
@@ -0,0 +1,8 @@
+#pragma once
+
+// A _Bool holds either 0 or 1. Any value converted to it is 1 unless
+// it is 0.
+#define bool _Bool
+#define true 1
+#define false 0
+#define __bool_true_false_are_defined 1
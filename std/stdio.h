@@ -1,2 +1,4 @@
+#pragma once
+
 extern void putchar(char c);
 extern void puts(char *s);